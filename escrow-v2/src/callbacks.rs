@@ -1,47 +1,511 @@
-use near_sdk::{env, json_types::U128, log, near_bindgen, AccountId, Balance, PromiseResult};
+use near_contract_standards::fungible_token::events::FtBurn;
+use near_sdk::{
+    env, json_types::U128, near_bindgen, serde_json, AccountId, Balance, PromiseResult,
+};
 
+use crate::consts::*;
+use crate::events::*;
 use crate::storage::*;
 
 #[near_bindgen]
 impl Escrow {
+    /**
+     * The recipient was marked as claimed for token_id on claim_fees
+     * Unmark it if the transfer failed so it can be claimed again
+     */
     #[private]
-    pub fn on_withdraw_callback(&mut self, receiver_id: AccountId, amount: U128) -> Balance {
+    pub fn on_claim_fees_callback(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> bool {
+        let event_token_id = Some(&token_id).filter(|token_id| self.is_accepted_token(token_id));
+
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                ClaimFeesEvent {
+                    account_id: &account_id,
+                    amount,
+                    token_id: event_token_id,
+                }
+                .emit();
+
+                true
+            }
+            _ => {
+                if let Some(index) = self
+                    .fees
+                    .recipients
+                    .iter()
+                    .position(|recipient| recipient.account_id == account_id)
+                {
+                    self.internal_set_fee_claimed(index, &token_id, false);
+                }
+
+                ClaimFeesFailedEvent {
+                    account_id: &account_id,
+                    amount,
+                    token_id: event_token_id,
+                }
+                .emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * The referral fees were claimed on claim_referral_fees
+     * Restore them if the transfer failed so they can be claimed again
+     */
+    #[private]
+    pub fn on_claim_referral_fees_callback(
+        &mut self,
+        referrer_id: AccountId,
+        amount: U128,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                ClaimFeesEvent {
+                    account_id: &referrer_id,
+                    amount,
+                    token_id: None,
+                }
+                .emit();
+
+                true
+            }
+            _ => {
+                if let Some(mut stats) = self.referrals.get(&referrer_id) {
+                    stats.fees_claimed -= amount.0;
+                    self.referrals.insert(&referrer_id, &stats);
+                }
+
+                self.fees.referral_fees_claimed -= amount.0;
+
+                ClaimFeesFailedEvent {
+                    account_id: &referrer_id,
+                    amount,
+                    token_id: None,
+                }
+                .emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * The income was claimed on claim_income
+     * Restore it if the transfer failed so it can be claimed again
+     */
+    #[private]
+    pub fn on_claim_income_callback(&mut self, account_id: AccountId, amount: U128) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                ClaimIncomeEvent {
+                    account_id: &account_id,
+                    amount,
+                }
+                .emit();
+
+                true
+            }
+            _ => {
+                let mut income_account = self.income_accounts.get(&account_id).unwrap_or_default();
+                income_account.unclaimed += amount.0;
+                self.income_accounts.insert(&account_id, &income_account);
+                self.income.claimed -= amount.0;
+
+                ClaimIncomeFailedEvent {
+                    account_id: &account_id,
+                    amount,
+                }
+                .emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * The receipts were burned on redeem, and contributions taken out of the token contributions backing them
+     * Mint them back and restore the contributions and the buyback liquidity if the transfer failed
+     */
+    #[private]
+    pub fn on_redeem_callback(
+        &mut self,
+        account_id: AccountId,
+        receipts: U128,
+        amount: U128,
+        contributions: Vec<TokenContribution>,
+    ) -> bool {
+        if let Some(buyback) = self.buyback.as_mut() {
+            buyback.pending_redeems -= 1;
+        }
+
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                FtBurn {
+                    owner_id: &account_id,
+                    amount: &receipts,
+                    memo: None,
+                }
+                .emit();
+
+                RedeemEvent {
+                    account_id: &account_id,
+                    receipts,
+                    amount,
+                }
+                .emit();
+
+                true
+            }
+            _ => {
+                self.internal_before_balance_change(&account_id);
+                self.internal_checkpoint_supply();
+                self.ft.internal_deposit(&account_id, receipts.0);
+
+                let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+
+                for taken in contributions {
+                    let token_contribution = contribution.token_mut(&taken.token_id);
+                    token_contribution.amount += taken.amount;
+                    token_contribution.receipts += taken.receipts;
+                }
+
+                self.contributions.insert(&account_id, &contribution);
+
+                if let Some(buyback) = self.buyback.as_mut() {
+                    buyback.paid -= amount.0;
+                    buyback.redeemed -= receipts.0;
+                }
+
+                RedeemFailedEvent {
+                    account_id: &account_id,
+                    receipts,
+                    amount,
+                }
+                .emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * The buyback was closed on close_buyback
+     * Remove it once the unused liquidity is back with the maintainer, reopen it if the transfer failed
+     */
+    #[private]
+    pub fn on_close_buyback_callback(&mut self, amount: U128) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                self.buyback = None;
+
+                BuybackClosedEvent { amount }.emit();
+
+                true
+            }
+            _ => {
+                if let Some(buyback) = self.buyback.as_mut() {
+                    buyback.closed = false;
+                }
+
+                BuybackCloseFailedEvent { amount }.emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * The receipts were burned on settle
+     * Settling -> Closed once no receipt token is left
+     * Mint them back and restore the contributions and the proceeds if the transfer failed, reopening a closed settlement
+     */
+    #[private]
+    pub fn on_settle_callback(
+        &mut self,
+        account_id: AccountId,
+        receipts: U128,
+        amount: U128,
+        contributions: Vec<TokenContribution>,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                FtBurn {
+                    owner_id: &account_id,
+                    amount: &receipts,
+                    memo: None,
+                }
+                .emit();
+
+                SettleEvent {
+                    account_id: &account_id,
+                    receipts,
+                    amount,
+                }
+                .emit();
+
+                if self.ft.total_supply == 0 && self.status == EscrowStatus::Settling {
+                    self.internal_set_status(EscrowStatus::Closed);
+                }
+
+                true
+            }
+            _ => {
+                self.internal_before_balance_change(&account_id);
+                self.internal_checkpoint_supply();
+                self.ft.internal_deposit(&account_id, receipts.0);
+
+                let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+
+                for taken in contributions {
+                    let token_contribution = contribution.token_mut(&taken.token_id);
+                    token_contribution.amount += taken.amount;
+                    token_contribution.receipts += taken.receipts;
+                }
+
+                self.contributions.insert(&account_id, &contribution);
+
+                if let Some(settlement) = self.settlement.as_mut() {
+                    settlement.paid -= amount.0;
+                    settlement.burned -= receipts.0;
+                }
+
+                if self.status == EscrowStatus::Closed {
+                    self.internal_set_status(EscrowStatus::Settling);
+                }
+
+                SettleFailedEvent {
+                    account_id: &account_id,
+                    receipts,
+                    amount,
+                }
+                .emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * The sale fees were claimed on claim_fees
+     * Restore them if the transfer failed so they can be claimed again
+     */
+    #[private]
+    pub fn on_claim_sale_fees_callback(&mut self, account_id: AccountId, amount: U128) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_result) => {
+                ClaimFeesEvent {
+                    account_id: &account_id,
+                    amount,
+                    token_id: None,
+                }
+                .emit();
+
+                true
+            }
+            _ => {
+                if let Some(recipient) = self
+                    .fees
+                    .recipients
+                    .iter_mut()
+                    .find(|recipient| recipient.account_id == account_id)
+                {
+                    recipient.sale_claimed = false;
+                }
+
+                ClaimFeesFailedEvent {
+                    account_id: &account_id,
+                    amount,
+                    token_id: None,
+                }
+                .emit();
+
+                false
+            }
+        }
+    }
+
+    /**
+     * Compares the balance reported by token_id with what the ledger says the escrow should hold
+     * Emits a discrepancy event if they differ, and pauses every operation if it holds less
+     * and metadata.pause_on_discrepancy is set
+     * Returns whether the balances match, false if the balance could not be read
+     */
+    #[private]
+    pub fn on_reconcile_callback(&mut self, token_id: AccountId) -> bool {
+        let actual = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::from_slice::<U128>(&result)
+                    .unwrap_or_else(|_| env::panic_str("ERR_INVALID_BALANCE"))
+                    .0
+            }
+            _ => return false,
+        };
+        let expected = self.get_token_accounting(&token_id).held.0;
+
+        if actual == expected {
+            return true;
+        }
+
+        DiscrepancyEvent {
+            token_id: &token_id,
+            expected: U128(expected),
+            actual: U128(actual),
+        }
+        .emit();
+
+        if actual < expected && self.get_metadata().pause_on_discrepancy {
+            self.internal_set_paused(&ALL_OPERATIONS, true);
+        }
+
+        false
+    }
+
+    /**
+     * The receipts, and the fees if refundable, were debited on withdraw or refund_batch, with the token balance
+     * amount and fees_refund are in token_id, receipts in receipt tokens
+     * Restore them and record the account in refund_failures if the transfer failed, see retry_refund
+     * The account keeps its place in the deposit accounts, so refund_batch pages do not move
+     * Returns the amount transferred
+     */
+    #[private]
+    pub fn on_withdraw_callback(
+        &mut self,
+        account_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        receipts: U128,
+        amount: U128,
+        fees_refund: U128,
+    ) -> Balance {
+        let event_token_id = Some(&token_id).filter(|token_id| self.is_accepted_token(token_id));
+        let event_fees_refund = Some(fees_refund).filter(|fees_refund| fees_refund.0 > 0);
+
         match env::promise_result(0) {
             PromiseResult::Successful(_result) => {
-                self.ft.internal_withdraw(&receiver_id, amount.0);
-                self.deposits.remove(&receiver_id);
-                self.get_metadata().unpaid_amount = self
+                let fees_receipts = self.to_receipt_amount(&token_id, fees_refund.0);
+                self.fees.amount = self.fees.amount.saturating_sub(fees_receipts);
+                self.metadata.unpaid_amount = self
                     .metadata
                     .unpaid_amount
-                    .checked_add(amount.0)
+                    .checked_add(receipts.0 + fees_receipts)
                     .unwrap_or_else(|| env::panic_str("ERR_UNPAID_AMOUNT_OVERFLOW"));
 
-                log!(
-                    "[on_withdraw_callback]: receiver_id: {}, amount: {}",
-                    receiver_id,
-                    amount.0
-                );
+                let is_refunded = self.ft.accounts.get(&account_id).unwrap_or(0) == 0
+                    && self
+                        .contributions
+                        .get(&account_id)
+                        .is_none_or(|contribution| contribution.tokens.is_empty());
+
+                if is_refunded {
+                    self.refund_failures.remove(&account_id);
+                    self.contributions.remove(&account_id);
+                }
+
+                if receipts.0 > 0 {
+                    FtBurn {
+                        owner_id: &account_id,
+                        amount: &receipts,
+                        memo: None,
+                    }
+                    .emit();
+                }
+
+                WithdrawEvent {
+                    account_id: &account_id,
+                    receiver_id: Some(&receiver_id)
+                        .filter(|receiver_id| *receiver_id != &account_id),
+                    amount,
+                    fees_refund: event_fees_refund,
+                    token_id: event_token_id,
+                }
+                .emit();
+
+                amount.0 + fees_refund.0
+            }
+            _ => {
+                if receipts.0 > 0 {
+                    // The account may have unregistered while the transfer was pending
+                    if !self.ft.accounts.contains_key(&account_id) {
+                        self.ft.internal_register_account(&account_id);
+                    }
+
+                    self.internal_before_balance_change(&account_id);
+                    self.internal_checkpoint_supply();
+                    self.ft.internal_deposit(&account_id, receipts.0);
+                }
+
+                let mut token_balance = self.token_balances.get(&token_id).unwrap_or_default();
+                token_balance.amount += amount.0;
+                token_balance.fees += fees_refund.0;
+                self.token_balances.insert(&token_id, &token_balance);
 
-                amount.0
+                let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+                let token_contribution = contribution.token_mut(&token_id);
+                token_contribution.amount += amount.0;
+                token_contribution.fees += fees_refund.0;
+                token_contribution.receipts += receipts.0;
+                self.contributions.insert(&account_id, &contribution);
+
+                self.refund_failures.insert(&account_id);
+
+                RefundFailedEvent {
+                    account_id: &account_id,
+                    amount,
+                    fees_refund: event_fees_refund,
+                    token_id: event_token_id,
+                }
+                .emit();
+
+                0
             }
-            _ => env::panic_str("ERR_WITHDRAW_UNSUCCESSFUL"),
         }
     }
 
+    /**
+     * Delegating -> Delegated once every tranche is released, back to Succeeded otherwise or if the transfer failed
+     * The delegated amount of token_id is restored so delegate_funds sends it again
+     */
     #[private]
-    pub fn on_claim_fees_callback(&mut self, amount: U128) -> bool {
+    pub fn on_delegate_funds_callback(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> bool {
         match env::promise_result(0) {
             PromiseResult::Successful(_result) => {
-                log!(
-                    "[on_claim_fees_callback]: fees_account_id: {}, claim: {}",
-                    self.get_fees().account_id,
-                    amount.0,
-                );
+                let status = match self.is_fully_delegated() {
+                    true => EscrowStatus::Delegated,
+                    false => EscrowStatus::Succeeded,
+                };
+
+                self.internal_set_status(status);
+
+                DelegateFundsEvent {
+                    receiver_id: &receiver_id,
+                    amount,
+                    token_id: Some(&token_id).filter(|token_id| self.is_accepted_token(token_id)),
+                }
+                .emit();
 
-                self.fees.claimed = true;
                 true
             }
-            _ => env::panic_str("ERR_CLAIM_FEES_UNSUCCESSFUL"),
+            _ => {
+                let mut token_balance = self.token_balances.get(&token_id).unwrap_or_default();
+                token_balance.delegated -= amount.0;
+                self.token_balances.insert(&token_id, &token_balance);
+
+                self.internal_set_status(EscrowStatus::Succeeded);
+
+                false
+            }
         }
     }
 }
@@ -1,6 +1,32 @@
 use near_sdk::{Balance, Gas};
 
+use crate::storage::Operation;
+
 // Fungible Token
 pub const GAS_ON_TRANSFER: Gas = Gas(2_000_000_000_000);
 pub const GAS_ON_TRANSFER_CB: Gas = Gas(2_000_000_000_000);
+// on_reconcile_callback works out the accounting of a token, and may pause everything on a deficit
+pub const GAS_ON_RECONCILE_CB: Gas = Gas(10_000_000_000_000);
 pub const BALANCE_ON_STORAGE_DEPOSIT: Balance = 2_350_000_000_000_000_000_000; // 0.00235 NEAR
+
+// Native NEAR, listed in Metadata::accepted_tokens under this reserved token id
+pub const NEAR_TOKEN_ID: &str = "near";
+pub const NEAR_DECIMALS: u8 = 24;
+
+// Fees
+pub const FEE_BASIS_POINTS_DENOMINATOR: u32 = 10_000;
+
+// Income and settlement, the amounts per receipt unit are scaled by it
+pub const PER_SHARE_PRECISION: u128 = 1_000_000_000_000_000_000;
+
+// Pause, paused all at once on a reconcile deficit
+pub const ALL_OPERATIONS: [Operation; 4] = [
+    Operation::Deposits,
+    Operation::Withdrawals,
+    Operation::Delegation,
+    Operation::FeeClaims,
+];
+
+// Refunds, refund_batch stops before an account whose transfers no longer fit in the gas left
+pub const REFUND_BATCH_MAX_LIMIT: u64 = 15;
+pub const GAS_PER_REFUND_TRANSFER: Gas = Gas(25_000_000_000_000);
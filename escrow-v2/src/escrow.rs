@@ -1,27 +1,79 @@
 use near_sdk::{
     assert_one_yocto,
-    collections::{LazyOption, UnorderedSet},
+    collections::{LazyOption, LookupMap, UnorderedMap, UnorderedSet, Vector},
     env, ext_contract,
     json_types::U128,
-    log, near_bindgen,
+    near_bindgen,
     serde_json::json,
     AccountId, Balance, Promise, PromiseOrValue,
 };
 
 use near_contract_standards::fungible_token::{
-    core::ext_ft_core,
+    core::{ext_ft_core, FungibleTokenCore},
+    events::FtMint,
     metadata::{FungibleTokenMetadata, FungibleTokenMetadataProvider},
+    resolver::FungibleTokenResolver,
     FungibleToken,
 };
 
 use crate::consts::*;
+use crate::events::*;
 use crate::storage::*;
 
+use self::u256::U256;
+
+// Holds the products scaled by PER_SHARE_PRECISION, they overflow u128 at 18 decimals
+mod u256 {
+    #![allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+
 // Interface of this contract, for callbacks
 #[ext_contract(ext_self)]
 trait Callbacks {
-    fn on_withdraw_callback(&mut self, receiver_id: AccountId, amount: U128) -> Balance;
-    fn on_claim_fees_callback(&mut self, amount: U128) -> bool;
+    fn on_withdraw_callback(
+        &mut self,
+        account_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        receipts: U128,
+        amount: U128,
+        fees_refund: U128,
+    ) -> Balance;
+    fn on_claim_fees_callback(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> bool;
+    fn on_claim_referral_fees_callback(&mut self, referrer_id: AccountId, amount: U128) -> bool;
+    fn on_delegate_funds_callback(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> bool;
+    fn on_claim_income_callback(&mut self, account_id: AccountId, amount: U128) -> bool;
+    fn on_redeem_callback(
+        &mut self,
+        account_id: AccountId,
+        receipts: U128,
+        amount: U128,
+        contributions: Vec<TokenContribution>,
+    ) -> bool;
+    fn on_close_buyback_callback(&mut self, amount: U128) -> bool;
+    fn on_settle_callback(
+        &mut self,
+        account_id: AccountId,
+        receipts: U128,
+        amount: U128,
+        contributions: Vec<TokenContribution>,
+    ) -> bool;
+    fn on_claim_sale_fees_callback(&mut self, account_id: AccountId, amount: U128) -> bool;
+    fn on_reconcile_callback(&mut self, token_id: AccountId) -> bool;
 }
 
 impl Default for Escrow {
@@ -43,9 +95,106 @@ impl Escrow {
             env::panic_str("ERR_ALREADY_INITIALIZED");
         }
 
-        // Fungible Token Setup
+        let early_bird_basis_points = fees.early_bird.iter().map(|fee| fee.basis_points);
+        let volume_tiers_basis_points = fees.volume_tiers.iter().map(|fee| fee.basis_points);
+        let account_overrides_basis_points =
+            fees.account_overrides.iter().map(|fee| fee.basis_points);
+
+        if std::iter::once(fees.basis_points)
+            .chain(early_bird_basis_points)
+            .chain(volume_tiers_basis_points)
+            .chain(account_overrides_basis_points)
+            .chain(std::iter::once(fees.referral_basis_points))
+            .chain(std::iter::once(fees.sale_basis_points))
+            .any(|basis_points| basis_points > FEE_BASIS_POINTS_DENOMINATOR)
+        {
+            env::panic_str("ERR_INVALID_FEE_BASIS_POINTS");
+        }
+
+        let total_weight = fees
+            .recipients
+            .iter()
+            .try_fold(0u32, |total, recipient| total.checked_add(recipient.weight));
+
+        let has_duplicates = fees
+            .recipients
+            .iter()
+            .enumerate()
+            .any(|(index, recipient)| {
+                fees.recipients[..index]
+                    .iter()
+                    .any(|other| other.account_id == recipient.account_id)
+            });
+
+        if has_duplicates || !matches!(total_weight, Some(total_weight) if total_weight > 0) {
+            env::panic_str("ERR_INVALID_FEE_RECIPIENTS");
+        }
+
+        if fees.mint_policy == FeeMintPolicy::MintToRecipients && fees.referral_basis_points > 0 {
+            env::panic_str("ERR_INVALID_FEE_MINT_POLICY");
+        }
+
+        if let Some(max_per_account) = metadata.max_per_account {
+            if max_per_account == 0 || metadata.min_deposit.unwrap_or(0) > max_per_account {
+                env::panic_str("ERR_INVALID_DEPOSIT_LIMITS");
+            }
+        }
+
+        if let Some(soft_cap) = metadata.soft_cap {
+            if soft_cap == 0 || soft_cap > metadata.funding_amount_limit {
+                env::panic_str("ERR_INVALID_SOFT_CAP");
+            }
+        }
+
+        let has_invalid_token =
+            metadata
+                .accepted_tokens
+                .iter()
+                .enumerate()
+                .any(|(index, token)| {
+                    let decimals = token.decimals.abs_diff(fungible_token_metadata.decimals);
+
+                    token.account_id == metadata.nep_141
+                        || token.rate_basis_points == 0
+                        || (token.account_id.as_str() == NEAR_TOKEN_ID
+                            && token.decimals != NEAR_DECIMALS)
+                        || Balance::checked_pow(10, decimals.into()).is_none()
+                        || metadata.accepted_tokens[..index]
+                            .iter()
+                            .any(|other| other.account_id == token.account_id)
+                });
+
+        if has_invalid_token {
+            env::panic_str("ERR_INVALID_ACCEPTED_TOKENS");
+        }
+
+        if !metadata.tranches.is_empty() {
+            let total_basis_points = metadata.tranches.iter().try_fold(0u32, |total, tranche| {
+                total.checked_add(tranche.basis_points)
+            });
+
+            if total_basis_points != Some(FEE_BASIS_POINTS_DENOMINATOR)
+                || (metadata.approver_account_id.is_none() && metadata.voting.is_none())
+                || metadata
+                    .tranches
+                    .iter()
+                    .any(|tranche| tranche.basis_points == 0)
+            {
+                env::panic_str("ERR_INVALID_TRANCHES");
+            }
+        }
+
+        if let Some(voting) = &metadata.voting {
+            if voting.quorum_basis_points > FEE_BASIS_POINTS_DENOMINATOR
+                || voting.threshold_basis_points >= FEE_BASIS_POINTS_DENOMINATOR
+                || voting.voting_period == 0
+            {
+                env::panic_str("ERR_INVALID_VOTING_POLICY");
+            }
+        }
+
+        // Fungible Token Setup, total_supply grows with each deposit receipt
         let mut ft = FungibleToken::new(StorageKeys::FungibleToken);
-        ft.total_supply = metadata.funding_amount_limit;
         ft.internal_register_account(&metadata.maintainer_account_id);
 
         // Deposits Setup
@@ -55,15 +204,25 @@ impl Escrow {
         // Escrow Storage Deposit
         let storage_deposit_amount = storage_deposit_amount.unwrap_or(BALANCE_ON_STORAGE_DEPOSIT);
 
-        Promise::new(metadata.nep_141.clone()).function_call(
-            "storage_deposit".to_string(),
-            json!({ "account_id": env::current_account_id() })
-                .to_string()
-                .into_bytes(),
-            storage_deposit_amount,
-            GAS_ON_TRANSFER,
+        let token_ids = std::iter::once(&metadata.nep_141).chain(
+            metadata
+                .accepted_tokens
+                .iter()
+                .map(|token| &token.account_id)
+                .filter(|token_id| token_id.as_str() != NEAR_TOKEN_ID),
         );
 
+        for token_id in token_ids {
+            Promise::new(token_id.clone()).function_call(
+                "storage_deposit".to_string(),
+                json!({ "account_id": env::current_account_id() })
+                    .to_string()
+                    .into_bytes(),
+                storage_deposit_amount,
+                GAS_ON_TRANSFER,
+            );
+        }
+
         let mut this = Self {
             deposits,
             ft,
@@ -73,14 +232,51 @@ impl Escrow {
             ),
             metadata: Metadata {
                 unpaid_amount: metadata.funding_amount_limit,
+                tranches: metadata
+                    .tranches
+                    .into_iter()
+                    .map(|tranche| Tranche {
+                        approved: false,
+                        released: false,
+                        ..tranche
+                    })
+                    .collect(),
                 ..metadata
             },
             fees: Fees {
                 amount: 0,
-                claimed: false,
+                referral_amount: 0,
+                referral_fees_claimed: 0,
+                sale_amount: 0,
+                recipients: fees
+                    .recipients
+                    .into_iter()
+                    .map(|recipient| FeeRecipient {
+                        claimed: false,
+                        claimed_tokens: vec![],
+                        sale_claimed: false,
+                        ..recipient
+                    })
+                    .collect(),
                 ..fees
             },
             account_storage_usage: 0,
+            referrals: UnorderedMap::new(StorageKeys::Referrals),
+            contributions: UnorderedMap::new(StorageKeys::Contributions),
+            status: EscrowStatus::Funding,
+            refund_failures: UnorderedSet::new(StorageKeys::RefundFailures),
+            token_balances: UnorderedMap::new(StorageKeys::TokenBalances),
+            proposal: None,
+            votes: UnorderedMap::new(StorageKeys::Votes),
+            snapshots: Vector::new(StorageKeys::Snapshots),
+            balance_checkpoints: LookupMap::new(StorageKeys::BalanceCheckpoints),
+            supply_checkpoints: Vector::new(StorageKeys::SupplyCheckpoints),
+            income: Income::default(),
+            income_accounts: LookupMap::new(StorageKeys::IncomeAccounts),
+            buyback: None,
+            settlement: None,
+            pause: Pause::default(),
+            pending_guardian_account_id: None,
         };
 
         this.measure_account_storage_usage();
@@ -92,19 +288,72 @@ impl Escrow {
      * Called on ft_transfer_callback only
      * Total sender balances must match the contract NEP141 balance, minus fees
      * Transfer self NEP141 of the stable NEP141 amount as a receipt
+     * If instruction.referrer_id is set, the referrer gets referral_basis_points of the fee
+     * If instruction.beneficiary_id is set, the beneficiary gets the receipt and may withdraw it, not the sender
+     * Accepts up to unpaid_amount and returns the unused amount, refunded by the NEP141 on ft_resolve_transfer
+     * The account total contribution is capped by max_per_account and must reach min_deposit
+     * token_id is nep_141 or an accepted token, amount is in its own units and converted to nep_141 units
      */
     #[private]
-    pub fn deposit(&mut self, sender_id: AccountId, amount: Balance) {
-        if !self.is_deposit_allowed() {
-            env::panic_str("ERR_DEPOSIT_NOT_ALLOWED");
+    pub fn deposit(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        instruction: DepositInstruction,
+    ) -> Balance {
+        self.assert_status(&[EscrowStatus::Funding], "ERR_DEPOSIT_NOT_ALLOWED");
+
+        let account_id = instruction
+            .beneficiary_id
+            .unwrap_or_else(|| sender_id.clone());
+
+        if let Some(referrer_id) = &instruction.referrer_id {
+            if referrer_id == &sender_id || referrer_id == &account_id {
+                env::panic_str("ERR_INVALID_REFERRER");
+            }
+        }
+
+        let deposited_amount = amount;
+        let amount = self.to_receipt_amount(&token_id, deposited_amount);
+
+        if amount == 0 {
+            env::panic_str("ERR_ZERO_AMOUNT");
+        }
+
+        // Partial fill of the last deposit of the round or of the account limit
+        let unpaid_amount = self.get_metadata().unpaid_amount;
+        let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+        let account_limit = match self.get_metadata().max_per_account {
+            Some(max_per_account) => max_per_account.saturating_sub(contribution.amount),
+            None => Balance::MAX,
+        };
+
+        if account_limit == 0 {
+            env::panic_str("ERR_MAX_PER_ACCOUNT_REACHED");
         }
 
-        if amount > self.get_metadata().unpaid_amount {
-            env::panic_str("ERR_AMOUNT_GT_UNPAID_AMOUNT");
+        // The refund is in the deposited NEP141, the part kept is rounded up in favor of the escrow
+        let accepted_amount = amount.min(unpaid_amount.min(account_limit));
+        let token_amount = if accepted_amount < amount {
+            self.to_token_amount(&token_id, accepted_amount)
+                .min(deposited_amount)
+        } else {
+            deposited_amount
+        };
+        let refund = deposited_amount - token_amount;
+        let amount = accepted_amount;
+
+        if let Some(min_deposit) = self.get_metadata().min_deposit {
+            if contribution.amount + amount < min_deposit && amount < unpaid_amount {
+                env::panic_str("ERR_AMOUNT_LT_MIN_DEPOSIT");
+            }
         }
 
         // Fee Calculations
-        let fee_amount = (amount as f32 * self.get_fees().percentage) as Balance;
+        let (fee_rule, basis_points) = self.get_fee_rule(&sender_id, amount);
+        let fee_amount =
+            Self::calculate_fraction(amount, basis_points, FEE_BASIS_POINTS_DENOMINATOR);
         self.fees.amount = self
             .get_fees()
             .amount
@@ -113,141 +362,2079 @@ impl Escrow {
         let amount_minus_fee = amount
             .checked_sub(fee_amount)
             .unwrap_or_else(|| env::panic_str("ERR_AMOUNT_MINUS_FEE_OVERFLOW"));
+        let token_fee_amount =
+            Self::calculate_fraction(token_amount, basis_points, FEE_BASIS_POINTS_DENOMINATOR);
+
+        let mint_fees = self.fees.mint_policy == FeeMintPolicy::MintToRecipients;
+
+        let mut token_balance = self.token_balances.get(&token_id).unwrap_or_default();
+        match mint_fees {
+            true => token_balance.amount += token_amount,
+            false => {
+                token_balance.amount += token_amount - token_fee_amount;
+                token_balance.fees += token_fee_amount;
+            }
+        }
+        self.token_balances.insert(&token_id, &token_balance);
 
         // Register transfer
-        match self.ft.accounts.get(&sender_id) {
+        match self.ft.accounts.get(&account_id) {
             None => {
-                self.ft.internal_register_account(&sender_id);
+                self.ft.internal_register_account(&account_id);
             }
             Some(_acccount) => {}
         }
 
-        self.ft.internal_deposit(&sender_id, amount_minus_fee);
-        self.deposits.insert(&sender_id);
+        self.internal_before_balance_change(&account_id);
+        self.internal_checkpoint_supply();
+        self.ft.internal_deposit(&account_id, amount_minus_fee);
+        self.deposits.insert(&account_id);
+        contribution.amount += amount;
+        contribution.fees += fee_amount;
+        let token_contribution = contribution.token_mut(&token_id);
+        token_contribution.amount += token_amount - token_fee_amount;
+        token_contribution.receipts += amount_minus_fee;
+        if !mint_fees {
+            token_contribution.fees += token_fee_amount;
+        }
+        self.contributions.insert(&account_id, &contribution);
         self.metadata.unpaid_amount = self
             .metadata
             .unpaid_amount
             .checked_sub(amount)
             .unwrap_or_else(|| env::panic_str("ERR_UNPAID_AMOUNT_OVERFLOW"));
 
-        log!(
-            "[deposit]: sender_id: {}, amount_minus_fee: {}, fee: {}",
-            sender_id,
-            amount_minus_fee,
-            fee_amount
-        );
+        FtMint {
+            owner_id: &account_id,
+            amount: &U128(amount_minus_fee),
+            memo: None,
+        }
+        .emit();
+
+        DepositEvent {
+            account_id: &account_id,
+            amount: U128(amount_minus_fee),
+            fee: U128(fee_amount),
+            fee_rule,
+            sender_id: Some(&sender_id).filter(|sender_id| *sender_id != &account_id),
+            refund: Some(U128(refund)).filter(|refund| refund.0 > 0),
+            token_id: Some(&token_id).filter(|token_id| self.is_accepted_token(token_id)),
+        }
+        .emit();
+
+        if mint_fees {
+            self.internal_mint_fees(&token_id, fee_amount, token_fee_amount);
+        }
+
+        // Deposits in an accepted token earn no referral fee
+        if let Some(referrer_id) = instruction.referrer_id {
+            let fee_amount = match self.is_accepted_token(&token_id) {
+                true => 0,
+                false => fee_amount,
+            };
+
+            self.internal_record_referral(&referrer_id, &sender_id, amount, fee_amount);
+        }
+
+        // Funding -> Succeeded once funding_amount_limit is reached
+        self.internal_set_status(self.get_status());
+
+        refund
+    }
+
+    /**
+     * Deposit of the attached NEAR, if NEAR_TOKEN_ID is one of the accepted tokens
+     * Same fees, receipts and limits as a NEP141 deposit, the unused NEAR is refunded to the predecessor
+     * instruction may only name a referrer_id or a beneficiary_id
+     * Returns the refund
+     */
+    #[payable]
+    pub fn deposit_near(&mut self, instruction: Option<DepositInstruction>) -> U128 {
+        self.assert_not_paused(Operation::Deposits);
+
+        let token_id = AccountId::new_unchecked(NEAR_TOKEN_ID.to_string());
+
+        if !self.is_accepted_token(&token_id) {
+            env::panic_str("ERR_NEAR_NOT_ACCEPTED");
+        }
+
+        let amount = env::attached_deposit();
+
+        if amount == 0 {
+            env::panic_str("ERR_ZERO_AMOUNT");
+        }
+
+        let instruction = instruction.unwrap_or_default();
+
+        // Income, buyback liquidity and sale proceeds are only paid in nep_141
+        if instruction.income || instruction.buyback.is_some() || instruction.sale {
+            env::panic_str("ERR_INVALID_MSG");
+        }
+
+        let sender_id = env::predecessor_account_id();
+        let refund = self.deposit(sender_id.clone(), token_id, amount, instruction);
+
+        if refund > 0 {
+            Promise::new(sender_id).transfer(refund);
+        }
+
+        U128(refund)
     }
 
     /**
      * Only if total funds are not reached or escrow has expired
      * Transfer all funds to receiver_id
+     * If the escrow was cancelled with refundable fees, the fees paid by receiver_id are returned too
+     * The balance is debited before the transfer, so concurrent calls have nothing left to withdraw
+     * The balance of the predecessor is withdrawn, so multisigs, DAOs and relayed calls recover their own funds
+     * receiver_id defaults to the predecessor
+     * Each NEP141 backing the receipt tokens is returned in its own transfer, see TokenContribution
+     */
+    #[payable]
+    pub fn withdraw(&mut self, receiver_id: Option<AccountId>) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused(Operation::Withdrawals);
+
+        self.assert_status(
+            &[EscrowStatus::Failed, EscrowStatus::Cancelled],
+            "ERR_WITHDRAWAL_NOT_ALLOWED",
+        );
+
+        let account_id = env::predecessor_account_id();
+        let receiver_id = receiver_id.unwrap_or_else(|| account_id.clone());
+
+        self.internal_refund(&account_id, &receiver_id)
+            .unwrap_or_else(|| env::panic_str("ERR_NOTHING_TO_WITHDRAW"))
+    }
+
+    /**
+     * Anyone may push the refunds of a failed or cancelled escrow, limit accounts at a time
+     * Stops before an account whose transfers, one per NEP141, need more than the gas left
+     * Refunded accounts keep their place in the deposit accounts, so following next_index from 0
+     * until the number of deposit accounts covers everyone
+     * Accounts in refund_failures are skipped, retry_refund retries them one at a time
+     * Returns the number of refunds sent and the index the next batch starts from
+     */
+    pub fn refund_batch(&mut self, from_index: u64, limit: u64) -> RefundBatch {
+        self.assert_not_paused(Operation::Withdrawals);
+        self.assert_status(
+            &[EscrowStatus::Failed, EscrowStatus::Cancelled],
+            "ERR_REFUND_NOT_ALLOWED",
+        );
+
+        if limit == 0 || limit > REFUND_BATCH_MAX_LIMIT {
+            env::panic_str("ERR_INVALID_LIMIT");
+        }
+
+        let to_index = self.deposits.len().min(from_index.saturating_add(limit));
+        let mut batch = RefundBatch {
+            refunds: 0,
+            next_index: from_index,
+        };
+
+        for index in from_index..to_index {
+            let account_id = self.deposits.as_vector().get(index).unwrap();
+
+            if !self.refund_failures.contains(&account_id) {
+                let gas = GAS_PER_REFUND_TRANSFER.0 * self.get_refund_transfers(&account_id);
+
+                if env::prepaid_gas().0 - env::used_gas().0 < gas {
+                    break;
+                }
+
+                if self.internal_refund(&account_id, &account_id).is_some() {
+                    batch.refunds += 1;
+                }
+            }
+
+            batch.next_index = index + 1;
+        }
+
+        if batch.next_index == from_index && from_index < to_index {
+            env::panic_str("ERR_NOT_ENOUGH_GAS");
+        }
+
+        batch
+    }
+
+    /**
+     * Anyone may retry the refund of an account whose refund_batch transfer failed
+     */
+    pub fn retry_refund(&mut self, account_id: AccountId) -> Promise {
+        self.assert_not_paused(Operation::Withdrawals);
+        self.assert_status(
+            &[EscrowStatus::Failed, EscrowStatus::Cancelled],
+            "ERR_REFUND_NOT_ALLOWED",
+        );
+
+        if !self.refund_failures.contains(&account_id) {
+            env::panic_str("ERR_NO_REFUND_FAILURE");
+        }
+
+        self.internal_refund(&account_id, &account_id)
+            .unwrap_or_else(|| env::panic_str("ERR_NOTHING_TO_WITHDRAW"))
+    }
+
+    /**
+     * Transfer each recipient its share of the fees, one transfer per recipient and NEP141
+     * With FeeMintPolicy::MintToRecipients the deposit fees were minted, only a sale fee is left to claim
+     */
+    #[payable]
+    pub fn claim_fees(&mut self) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused(Operation::FeeClaims);
+
+        self.assert_status(
+            &[
+                EscrowStatus::Succeeded,
+                EscrowStatus::Failed,
+                EscrowStatus::Delegating,
+                EscrowStatus::Delegated,
+                EscrowStatus::Cancelled,
+                EscrowStatus::Settling,
+                EscrowStatus::Closed,
+            ],
+            "ERR_CLAIM_FEES_NOT_ALLOWED",
+        );
+
+        if self.get_fees().refundable {
+            env::panic_str("ERR_FEES_REFUNDABLE");
+        }
+
+        if self.fees.mint_policy == FeeMintPolicy::MintToRecipients && self.fees.sale_amount == 0 {
+            env::panic_str("ERR_FEES_MINTED");
+        }
+
+        let mut promises = vec![];
+        let token_ids: Vec<AccountId> = self.token_balances.keys().collect();
+
+        for index in 0..self.fees.recipients.len() {
+            for token_id in token_ids.iter() {
+                if self.is_fee_claimed(index, token_id) {
+                    continue;
+                }
+
+                let receiver_id = self.fees.recipients[index].account_id.clone();
+                let amount = U128(self.get_fee_recipient_share(index, token_id));
+
+                if amount.0 == 0 {
+                    continue;
+                }
+
+                self.internal_set_fee_claimed(index, token_id, true);
+
+                let promise = Self::internal_transfer_token(token_id, &receiver_id, amount);
+
+                let callback = ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_ON_TRANSFER_CB)
+                    .with_attached_deposit(0)
+                    .on_claim_fees_callback(receiver_id, token_id.clone(), amount);
+
+                promises.push(promise.then(callback));
+            }
+
+            let sale_amount = self.get_fee_share(index, self.fees.sale_amount);
+
+            if !self.fees.recipients[index].sale_claimed && sale_amount > 0 {
+                self.fees.recipients[index].sale_claimed = true;
+
+                let receiver_id = self.fees.recipients[index].account_id.clone();
+                let promise = Self::internal_transfer_token(
+                    &self.get_metadata().nep_141,
+                    &receiver_id,
+                    U128(sale_amount),
+                );
+
+                let callback = ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_ON_TRANSFER_CB)
+                    .with_attached_deposit(0)
+                    .on_claim_sale_fees_callback(receiver_id, U128(sale_amount));
+
+                promises.push(promise.then(callback));
+            }
+        }
+
+        promises
+            .into_iter()
+            .reduce(|promises, promise| promises.and(promise))
+            .unwrap_or_else(|| env::panic_str("ERR_FEES_ALREADY_CLAIMED"))
+    }
+
+    /**
+     * Transfer the referral fees of the predecessor
+     * Claimed before the transfer, restored on on_claim_referral_fees_callback if it fails
      */
     #[payable]
-    pub fn withdraw(&mut self) -> Promise {
+    pub fn claim_referral_fees(&mut self) -> Promise {
         assert_one_yocto();
+        self.assert_not_paused(Operation::FeeClaims);
+
+        self.assert_status(
+            &[
+                EscrowStatus::Succeeded,
+                EscrowStatus::Failed,
+                EscrowStatus::Delegating,
+                EscrowStatus::Delegated,
+                EscrowStatus::Cancelled,
+                EscrowStatus::Settling,
+                EscrowStatus::Closed,
+            ],
+            "ERR_CLAIM_FEES_NOT_ALLOWED",
+        );
+
+        if self.get_fees().refundable {
+            env::panic_str("ERR_FEES_REFUNDABLE");
+        }
+
+        let referrer_id = env::predecessor_account_id();
+        let mut stats = self
+            .referrals
+            .get(&referrer_id)
+            .unwrap_or_else(|| env::panic_str("ERR_REFERRER_NOT_FOUND"));
 
-        if !self.is_withdrawal_allowed() {
-            env::panic_str("ERR_WITHDRAWAL_NOT_ALLOWED");
+        let amount = stats.fees_amount - stats.fees_claimed;
+
+        if amount == 0 {
+            env::panic_str("ERR_FEES_ALREADY_CLAIMED");
         }
 
-        let receiver_id = env::signer_account_id();
-        let amount = U128(self.ft.internal_unwrap_balance_of(&receiver_id));
+        stats.fees_claimed = stats.fees_amount;
+        self.referrals.insert(&referrer_id, &stats);
+        self.fees.referral_fees_claimed += amount;
 
         // NEP141 Transfer
         let promise = ext_ft_core::ext(self.get_metadata().nep_141.clone())
             .with_attached_deposit(1)
             .with_static_gas(GAS_ON_TRANSFER)
-            .ft_transfer(receiver_id.clone(), amount, None);
+            .ft_transfer(referrer_id.clone(), U128(amount), None);
 
         let callback = ext_self::ext(env::current_account_id())
             .with_static_gas(GAS_ON_TRANSFER_CB)
             .with_attached_deposit(0)
-            .on_withdraw_callback(receiver_id.clone(), amount);
+            .on_claim_referral_fees_callback(referrer_id, U128(amount));
 
         promise.then(callback)
     }
 
+    /**
+     * Only the maintainer, before the funds are delegated
+     * Funding | Succeeded -> Cancelled, withdraw is allowed right away
+     * If refund_fees, the fees collected are returned on withdraw instead of claimed
+     */
+    pub fn cancel(&mut self, refund_fees: Option<bool>) {
+        if env::predecessor_account_id() != self.get_metadata().maintainer_account_id {
+            env::panic_str("ERR_ONLY_MAINTAINER");
+        }
+
+        self.assert_status(
+            &[EscrowStatus::Funding, EscrowStatus::Succeeded],
+            "ERR_CANCEL_NOT_ALLOWED",
+        );
+
+        // Back in Succeeded after a partially failed delegation
+        if self
+            .token_balances
+            .values()
+            .any(|token_balance| token_balance.delegated > 0)
+        {
+            env::panic_str("ERR_CANCEL_NOT_ALLOWED");
+        }
+
+        let refund_fees = refund_fees.unwrap_or(false);
+
+        if refund_fees {
+            let fees_claimed = self
+                .fees
+                .recipients
+                .iter()
+                .any(|recipient| recipient.claimed || !recipient.claimed_tokens.is_empty())
+                || self.fees.referral_fees_claimed > 0;
+
+            if fees_claimed {
+                env::panic_str("ERR_FEES_ALREADY_CLAIMED");
+            }
+
+            self.fees.refundable = true;
+            self.fees.referral_amount = 0;
+        }
+
+        self.internal_set_status(EscrowStatus::Cancelled);
+
+        CancelEvent { refund_fees }.emit();
+    }
+
+    /**
+     * Only the approver, once the funding succeeded
+     * Allows the maintainer to release the tranche at index on delegate_funds
+     */
+    pub fn approve_tranche(&mut self, index: u32) {
+        if Some(env::predecessor_account_id()) != self.get_metadata().approver_account_id {
+            env::panic_str("ERR_ONLY_APPROVER");
+        }
+
+        self.assert_status(
+            &[EscrowStatus::Succeeded, EscrowStatus::Delegating],
+            "ERR_APPROVE_NOT_ALLOWED",
+        );
+
+        let tranche = self
+            .metadata
+            .tranches
+            .get_mut(index as usize)
+            .unwrap_or_else(|| env::panic_str("ERR_TRANCHE_NOT_FOUND"));
+
+        if tranche.approved {
+            env::panic_str("ERR_TRANCHE_ALREADY_APPROVED");
+        }
+
+        tranche.approved = true;
+
+        TrancheApprovedEvent {
+            index,
+            milestone: &tranche.milestone,
+        }
+        .emit();
+    }
+
+    /**
+     * Only the maintainer, once the funding succeeded and if metadata.voting is set
+     * Opens a vote of the receipt holders on the next tranche, or on the whole delegation without tranches
+     */
+    pub fn propose_delegation(&mut self) -> u64 {
+        if env::predecessor_account_id() != self.get_metadata().maintainer_account_id {
+            env::panic_str("ERR_ONLY_MAINTAINER");
+        }
+
+        self.assert_status(&[EscrowStatus::Succeeded], "ERR_PROPOSAL_NOT_ALLOWED");
+
+        let voting = self
+            .get_metadata()
+            .voting
+            .unwrap_or_else(|| env::panic_str("ERR_VOTING_DISABLED"));
+
+        if let Some(proposal) = &self.proposal {
+            if env::block_timestamp() <= proposal.ends_at {
+                env::panic_str("ERR_PROPOSAL_ACTIVE");
+            }
+        }
+
+        // A passed tranche proposal is kept as the approval of its tranche
+        if let Some(index) = self.proposal.as_ref().and_then(|proposal| proposal.tranche) {
+            if self.is_proposal_passed(Some(index)) {
+                self.metadata.tranches[index as usize].approved = true;
+            }
+        }
+
+        let tranche = match self.metadata.tranches.is_empty() {
+            true => None,
+            false => {
+                let index = self
+                    .metadata
+                    .tranches
+                    .iter()
+                    .position(|tranche| !tranche.approved)
+                    .unwrap_or_else(|| env::panic_str("ERR_TRANCHE_ALREADY_APPROVED"));
+
+                Some(index as u32)
+            }
+        };
+
+        if tranche.is_none() && self.is_proposal_passed(None) {
+            env::panic_str("ERR_DELEGATION_ALREADY_APPROVED");
+        }
+
+        let proposal = Proposal {
+            id: self.proposal.as_ref().map_or(0, |proposal| proposal.id + 1),
+            tranche,
+            ends_at: env::block_timestamp() + voting.voting_period,
+            approve_weight: 0,
+            reject_weight: 0,
+        };
+
+        ProposalEvent {
+            id: proposal.id,
+            tranche,
+            ends_at: proposal.ends_at,
+        }
+        .emit();
+
+        self.proposal = Some(proposal);
+
+        self.proposal.as_ref().unwrap().id
+    }
+
+    /**
+     * Vote of the predecessor on the open proposal, weighted by its receipt balance
+     * One vote per account, the weight drops with the balance until the proposal ends
+     */
+    pub fn vote(&mut self, approve: bool) {
+        self.assert_status(&[EscrowStatus::Succeeded], "ERR_VOTE_NOT_ALLOWED");
+
+        let account_id = env::predecessor_account_id();
+        let mut proposal = self
+            .proposal
+            .clone()
+            .filter(|proposal| env::block_timestamp() <= proposal.ends_at)
+            .unwrap_or_else(|| env::panic_str("ERR_NO_ACTIVE_PROPOSAL"));
+
+        if let Some(vote) = self.votes.get(&account_id) {
+            if vote.proposal_id == proposal.id {
+                env::panic_str("ERR_ALREADY_VOTED");
+            }
+        }
+
+        let weight = self.ft.accounts.get(&account_id).unwrap_or(0);
+
+        if weight == 0 {
+            env::panic_str("ERR_NO_VOTING_POWER");
+        }
+
+        match approve {
+            true => proposal.approve_weight += weight,
+            false => proposal.reject_weight += weight,
+        }
+
+        self.votes.insert(
+            &account_id,
+            &Vote {
+                proposal_id: proposal.id,
+                approve,
+                weight,
+            },
+        );
+
+        VoteEvent {
+            proposal_id: proposal.id,
+            account_id: &account_id,
+            approve,
+            weight: U128(weight),
+        }
+        .emit();
+
+        self.proposal = Some(proposal);
+    }
+
+    /**
+     * Transfer the income earned by the predecessor with its receipt tokens
+     * on_claim_income_callback restores it if the transfer fails
+     */
     #[payable]
-    pub fn claim_fees(&mut self) -> Promise {
+    pub fn claim_income(&mut self) -> Promise {
         assert_one_yocto();
+        self.assert_not_paused(Operation::Withdrawals);
 
-        if self.is_deposit_allowed() {
-            env::panic_str("ERR_CLAIM_FEES_NOT_ALLOWED");
-        }
+        let account_id = env::predecessor_account_id();
+        let mut income_account = self.get_income_account(&account_id);
+        let amount = income_account.unclaimed;
 
-        if self.get_fees().claimed {
-            env::panic_str("ERR_FEES_ALREADY_CLAIMED");
+        if amount == 0 {
+            env::panic_str("ERR_NO_INCOME");
         }
 
-        let fees_amount = U128(self.get_fees().amount);
-        let receiver_id = self.get_fees().account_id.clone();
+        income_account.unclaimed = 0;
+        self.income_accounts.insert(&account_id, &income_account);
+        self.income.claimed += amount;
 
-        // NEP141 Transfer
         let promise = ext_ft_core::ext(self.get_metadata().nep_141.clone())
             .with_attached_deposit(1)
             .with_static_gas(GAS_ON_TRANSFER)
-            .ft_transfer(receiver_id.clone(), fees_amount, None);
+            .ft_transfer(account_id.clone(), U128(amount), None);
 
         let callback = ext_self::ext(env::current_account_id())
             .with_static_gas(GAS_ON_TRANSFER_CB)
             .with_attached_deposit(0)
-            .on_claim_fees_callback(fees_amount);
+            .on_claim_income_callback(account_id, U128(amount));
 
         promise.then(callback)
     }
 
     /**
-     * Only if total funds are reached, allow to call this function
-     * Transfer total NEP141 funds to a new DAO
-     * Make the depositors members of the DAO
+     * Burns amount receipt tokens of the predecessor for their price in the open buyback
+     * The token contributions of the receipts are dropped, the funds were already delegated
+     * on_redeem_callback mints the receipts back and restores the contributions if the transfer fails
      */
     #[payable]
-    pub fn delegate_funds(&mut self, amount: Option<U128>) -> Promise {
+    pub fn redeem(&mut self, amount: U128) -> Promise {
         assert_one_yocto();
+        self.assert_not_paused(Operation::Withdrawals);
+
+        let account_id = env::predecessor_account_id();
+        let mut buyback = self
+            .buyback
+            .clone()
+            .filter(|buyback| !buyback.closed && env::block_timestamp() <= buyback.ends_at)
+            .unwrap_or_else(|| env::panic_str("ERR_NO_ACTIVE_BUYBACK"));
 
-        if self.is_deposit_allowed() || self.is_withdrawal_allowed() {
-            env::panic_str("ERR_DELEGATE_NOT_ALLOWED");
+        if amount.0 > self.ft.accounts.get(&account_id).unwrap_or(0) {
+            env::panic_str("ERR_NOT_ENOUGH_BALANCE");
         }
 
-        let fees_amount = self.get_fees().amount;
-        let receiver_id = self.get_metadata().maintainer_account_id.clone();
+        let payout = Self::calculate_fraction(
+            amount.0,
+            buyback.price_basis_points,
+            FEE_BASIS_POINTS_DENOMINATOR,
+        );
 
-        // If amount is None then use the funding_amount_limit
-        let amount = amount.unwrap_or(U128(self.get_metadata().funding_amount_limit));
+        if payout == 0 {
+            env::panic_str("ERR_ZERO_AMOUNT");
+        }
 
-        // Amount to delegate minus fees collected
-        let amount_minus_fee = amount
-            .0
-            .checked_sub(fees_amount)
-            .unwrap_or_else(|| env::panic_str("ERR_AMOUNT_MINUS_FEE_OVERFLOW"));
+        if payout > buyback.liquidity - buyback.paid {
+            env::panic_str("ERR_NOT_ENOUGH_LIQUIDITY");
+        }
 
-        log!(
-            "[on_delegate_funds]: receiver_id: {}, amount: {}",
-            receiver_id,
-            amount_minus_fee
-        );
+        buyback.paid += payout;
+        buyback.redeemed += amount.0;
+        buyback.pending_redeems += 1;
+        self.buyback = Some(buyback);
 
-        // NEP141 Transfer
-        ext_ft_core::ext(self.get_metadata().nep_141.clone())
+        self.internal_before_balance_change(&account_id);
+        self.internal_checkpoint_supply();
+        self.ft.internal_withdraw(&account_id, amount.0);
+
+        let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+        let taken = self.take_token_contributions(&mut contribution, amount.0);
+        contribution
+            .tokens
+            .retain(|token| token.receipts > 0 || token.fees > 0);
+        self.contributions.insert(&account_id, &contribution);
+
+        self.internal_sync_vote_weight(&account_id);
+
+        let promise = ext_ft_core::ext(self.get_metadata().nep_141.clone())
             .with_attached_deposit(1)
             .with_static_gas(GAS_ON_TRANSFER)
-            .ft_transfer(receiver_id.clone(), U128(amount_minus_fee), None)
-    }
-}
+            .ft_transfer(account_id.clone(), U128(payout), None);
 
-impl Escrow {
-    fn measure_account_storage_usage(&mut self) {
-        let initial_storage_usage = env::storage_usage();
-        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
-        self.deposits.insert(&tmp_account_id);
+        let callback = ext_self::ext(env::current_account_id())
+            .with_static_gas(GAS_ON_TRANSFER_CB)
+            .with_attached_deposit(0)
+            .on_redeem_callback(account_id, amount, U128(payout), taken);
+
+        promise.then(callback)
+    }
+
+    /**
+     * Anyone, once the buyback window is over and every redeem transfer settled
+     * Returns the unused liquidity to the maintainer, a new buyback may be opened after that
+     */
+    pub fn close_buyback(&mut self) -> PromiseOrValue<bool> {
+        self.assert_not_paused(Operation::Withdrawals);
+
+        let mut buyback = self
+            .buyback
+            .clone()
+            .filter(|buyback| !buyback.closed)
+            .unwrap_or_else(|| env::panic_str("ERR_NO_ACTIVE_BUYBACK"));
+
+        if env::block_timestamp() <= buyback.ends_at {
+            env::panic_str("ERR_BUYBACK_NOT_ENDED");
+        }
+
+        // A failed redeem transfer gives its payout back to the buyback
+        if buyback.pending_redeems > 0 {
+            env::panic_str("ERR_REDEEMS_PENDING");
+        }
+
+        let amount = buyback.liquidity - buyback.paid;
+
+        if amount == 0 {
+            self.buyback = None;
+
+            BuybackClosedEvent { amount: U128(0) }.emit();
+
+            return PromiseOrValue::Value(true);
+        }
+
+        buyback.closed = true;
+        self.buyback = Some(buyback);
+
+        let promise = ext_ft_core::ext(self.get_metadata().nep_141.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_ON_TRANSFER)
+            .ft_transfer(
+                self.get_metadata().maintainer_account_id,
+                U128(amount),
+                None,
+            );
+
+        let callback = ext_self::ext(env::current_account_id())
+            .with_static_gas(GAS_ON_TRANSFER_CB)
+            .with_attached_deposit(0)
+            .on_close_buyback_callback(U128(amount));
+
+        PromiseOrValue::Promise(promise.then(callback))
+    }
+
+    /**
+     * Burns every receipt token of the predecessor for its share of the sale proceeds
+     * The token contributions of the receipts are dropped, the funds were already delegated
+     * on_settle_callback mints the receipts back and restores the contributions if the transfer fails, and closes the escrow once none is left
+     */
+    #[payable]
+    pub fn settle(&mut self) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused(Operation::Withdrawals);
+
+        self.assert_status(&[EscrowStatus::Settling], "ERR_SETTLE_NOT_ALLOWED");
+
+        let account_id = env::predecessor_account_id();
+        let receipts = self.ft.accounts.get(&account_id).unwrap_or(0);
+
+        if receipts == 0 {
+            env::panic_str("ERR_NOT_ENOUGH_BALANCE");
+        }
+
+        let mut settlement = self.settlement.clone().unwrap();
+        let amount = match settlement.burned + receipts == settlement.supply {
+            true => settlement.proceeds - settlement.paid,
+            false => Self::mul_div(
+                receipts,
+                settlement.amount_per_share,
+                PER_SHARE_PRECISION,
+                "ERR_SETTLEMENT_OVERFLOW",
+            ),
+        };
+
+        settlement.paid += amount;
+        settlement.burned += receipts;
+        self.settlement = Some(settlement);
+
+        self.internal_before_balance_change(&account_id);
+        self.internal_checkpoint_supply();
+        self.ft.internal_withdraw(&account_id, receipts);
+
+        let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+        let taken = self.take_token_contributions(&mut contribution, receipts);
+        contribution
+            .tokens
+            .retain(|token| token.receipts > 0 || token.fees > 0);
+        self.contributions.insert(&account_id, &contribution);
+
+        let promise = ext_ft_core::ext(self.get_metadata().nep_141.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_ON_TRANSFER)
+            .ft_transfer(account_id.clone(), U128(amount), None);
+
+        let callback = ext_self::ext(env::current_account_id())
+            .with_static_gas(GAS_ON_TRANSFER_CB)
+            .with_attached_deposit(0)
+            .on_settle_callback(account_id, U128(receipts), U128(amount), taken);
+
+        promise.then(callback)
+    }
+
+    /**
+     * Anyone may check that the escrow holds what its ledger says, in nep_141 and each accepted NEP141
+     * on_reconcile_callback compares the balance reported by each NEP141 with get_token_accounting
+     */
+    pub fn reconcile(&mut self) -> Promise {
+        let nep_141 = self.get_metadata().nep_141;
+        let mut token_ids: Vec<AccountId> = self
+            .token_balances
+            .keys()
+            .filter(|token_id| token_id != &nep_141 && token_id.as_str() != NEAR_TOKEN_ID)
+            .collect();
+        token_ids.insert(0, nep_141);
+
+        token_ids
+            .into_iter()
+            .map(|token_id| {
+                let promise = ext_ft_core::ext(token_id.clone())
+                    .with_static_gas(GAS_ON_TRANSFER)
+                    .ft_balance_of(env::current_account_id());
+
+                let callback = ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_ON_RECONCILE_CB)
+                    .with_attached_deposit(0)
+                    .on_reconcile_callback(token_id);
+
+                promise.then(callback)
+            })
+            .reduce(|promises, promise| promises.and(promise))
+            .unwrap()
+    }
+
+    /**
+     * Only the guardian, pauses each of operations until it is unpaused
+     */
+    pub fn pause(&mut self, operations: Vec<Operation>) {
+        self.assert_guardian();
+        self.internal_set_paused(&operations, true);
+    }
+
+    /**
+     * Only the guardian, resumes each of operations
+     */
+    pub fn unpause(&mut self, operations: Vec<Operation>) {
+        self.assert_guardian();
+        self.internal_set_paused(&operations, false);
+    }
+
+    /**
+     * Only the guardian, hands the role to guardian_account_id once it calls accept_guardian
+     */
+    pub fn propose_guardian(&mut self, guardian_account_id: AccountId) {
+        self.assert_guardian();
+
+        GuardianEvent {
+            guardian_account_id: &guardian_account_id,
+            pending: true,
+        }
+        .emit();
+
+        self.pending_guardian_account_id = Some(guardian_account_id);
+    }
+
+    /**
+     * Only the proposed guardian, takes the role over
+     */
+    pub fn accept_guardian(&mut self) {
+        let guardian_account_id = env::predecessor_account_id();
+
+        if self.pending_guardian_account_id.as_ref() != Some(&guardian_account_id) {
+            env::panic_str("ERR_ONLY_PENDING_GUARDIAN");
+        }
+
+        self.metadata.guardian_account_id = Some(guardian_account_id.clone());
+        self.pending_guardian_account_id = None;
+
+        GuardianEvent {
+            guardian_account_id: &guardian_account_id,
+            pending: false,
+        }
+        .emit();
+    }
+
+    /**
+     * The maintainer at any time, or anyone once metadata.snapshot_interval passed since the last snapshot
+     * Records the receipt balances as of now, read later with balance_of_at and total_supply_at
+     * Balances are not copied, each one is checkpointed on its first change after the snapshot
+     * Returns the snapshot id
+     */
+    pub fn snapshot(&mut self) -> u64 {
+        if env::predecessor_account_id() != self.get_metadata().maintainer_account_id {
+            let interval = self
+                .get_metadata()
+                .snapshot_interval
+                .unwrap_or_else(|| env::panic_str("ERR_ONLY_MAINTAINER"));
+
+            if let Some(last_snapshot) = self.get_snapshot(self.snapshots.len()) {
+                if env::block_timestamp() < last_snapshot.taken_at + interval {
+                    env::panic_str("ERR_SNAPSHOT_TOO_EARLY");
+                }
+            }
+        }
+
+        let snapshot = Snapshot {
+            id: self.snapshots.len() + 1,
+            taken_at: env::block_timestamp(),
+        };
+
+        self.snapshots.push(&snapshot);
+
+        SnapshotEvent {
+            id: snapshot.id,
+            taken_at: snapshot.taken_at,
+        }
+        .emit();
+
+        snapshot.id
+    }
+
+    /**
+     * Only the maintainer, if total funds are reached, or the escrow expired with the soft cap reached
+     * Succeeded -> Delegating, on_delegate_funds_callback moves it to Delegated once everything is released
+     * Transfer the funds of each NEP141, net of fees, to a new DAO
+     * With tranches, only the next tranche is released and it must be approved, amount must not be set
+     * Without tranches but with metadata.voting, the delegation must be approved by a vote
+     * Otherwise amount caps the nep_141 funds transferred, accepted tokens are always transferred in full
+     * Make the depositors members of the DAO
+     */
+    #[payable]
+    pub fn delegate_funds(&mut self, amount: Option<U128>) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused(Operation::Delegation);
+
+        if env::predecessor_account_id() != self.get_metadata().maintainer_account_id {
+            env::panic_str("ERR_ONLY_MAINTAINER");
+        }
+
+        self.assert_status(&[EscrowStatus::Succeeded], "ERR_DELEGATE_NOT_ALLOWED");
+
+        if self.metadata.tranches.is_empty()
+            && self.metadata.voting.is_some()
+            && !self.is_proposal_passed(None)
+        {
+            env::panic_str("ERR_DELEGATION_NOT_APPROVED");
+        }
+
+        // Share of the funds released once this delegation is done
+        let released_basis_points = match self.internal_release_next_tranche() {
+            Some(_) if amount.is_some() => env::panic_str("ERR_AMOUNT_WITH_TRANCHES"),
+            Some(basis_points) => basis_points,
+            None => FEE_BASIS_POINTS_DENOMINATOR,
+        };
+
+        let receiver_id = self.get_metadata().maintainer_account_id.clone();
+        let token_ids: Vec<AccountId> = self.token_balances.keys().collect();
+        let mut promises = vec![];
+
+        for token_id in token_ids {
+            let mut token_balance = self.token_balances.get(&token_id).unwrap();
+            let released_amount = Self::calculate_fraction(
+                token_balance.amount,
+                released_basis_points,
+                FEE_BASIS_POINTS_DENOMINATOR,
+            );
+            let mut delegate_amount = released_amount.saturating_sub(token_balance.delegated);
+
+            if let (Some(amount), false) = (amount, self.is_accepted_token(&token_id)) {
+                delegate_amount = delegate_amount.min(amount.0);
+            }
+
+            if delegate_amount == 0 {
+                continue;
+            }
+
+            // Delegated before the transfer, restored on on_delegate_funds_callback if it fails
+            token_balance.delegated += delegate_amount;
+            self.token_balances.insert(&token_id, &token_balance);
+
+            let promise =
+                Self::internal_transfer_token(&token_id, &receiver_id, U128(delegate_amount));
+
+            let callback = ext_self::ext(env::current_account_id())
+                .with_static_gas(GAS_ON_TRANSFER_CB)
+                .with_attached_deposit(0)
+                .on_delegate_funds_callback(receiver_id.clone(), token_id, U128(delegate_amount));
+
+            promises.push(promise.then(callback));
+        }
+
+        let promise = promises
+            .into_iter()
+            .reduce(|promises, promise| promises.and(promise))
+            .unwrap_or_else(|| env::panic_str("ERR_NOTHING_TO_DELEGATE"));
+
+        self.internal_set_status(EscrowStatus::Delegating);
+
+        promise
+    }
+}
+
+impl Escrow {
+    /**
+     * The number of transfers a refund of account_id sends, one per NEP141 it is refunded in
+     * Receipts without token contributions, from a migrated escrow, are refunded in nep_141
+     */
+    fn get_refund_transfers(&self, account_id: &AccountId) -> u64 {
+        let balance = self.ft.accounts.get(account_id).unwrap_or(0);
+        let contribution = self.contributions.get(account_id).unwrap_or_default();
+        let refunded = contribution
+            .tokens
+            .iter()
+            .filter(|token| token.receipts > 0 || (self.fees.refundable && token.fees > 0));
+
+        let backed: Balance = contribution.tokens.iter().map(|token| token.receipts).sum();
+
+        refunded.count() as u64 + u64::from(balance > backed)
+    }
+
+    /**
+     * Debits the receipt tokens of account_id, and its fees if refundable, from the account and the token balances,
+     * then transfers them to receiver_id
+     * One transfer per NEP141, on_withdraw_callback restores its part if it fails
+     * Returns None if there is nothing to refund
+     */
+    fn internal_refund(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+    ) -> Option<Promise> {
+        let balance = self.ft.accounts.get(account_id).unwrap_or(0);
+        let mut contribution = self.contributions.get(account_id).unwrap_or_default();
+        let mut refunds = self.take_token_contributions(&mut contribution, balance);
+
+        if self.fees.refundable {
+            for token in contribution
+                .tokens
+                .iter_mut()
+                .filter(|token| token.fees > 0)
+            {
+                match refunds
+                    .iter_mut()
+                    .find(|refund| refund.token_id == token.token_id)
+                {
+                    Some(refund) => refund.fees = token.fees,
+                    None => refunds.push(TokenContribution {
+                        token_id: token.token_id.clone(),
+                        amount: 0,
+                        fees: token.fees,
+                        receipts: 0,
+                    }),
+                }
+
+                token.fees = 0;
+            }
+        }
+
+        if refunds.is_empty() {
+            return None;
+        }
+
+        // An account refunded only its fees may have unregistered its receipt token account
+        if balance > 0 {
+            self.internal_before_balance_change(account_id);
+            self.internal_checkpoint_supply();
+            self.ft.internal_withdraw(account_id, balance);
+        }
+        contribution.tokens.retain(|token| token.receipts > 0);
+        self.contributions.insert(account_id, &contribution);
+
+        for refund in refunds.iter() {
+            let mut token_balance = self
+                .token_balances
+                .get(&refund.token_id)
+                .unwrap_or_default();
+            token_balance.amount = token_balance.amount.saturating_sub(refund.amount);
+            token_balance.fees = token_balance.fees.saturating_sub(refund.fees);
+            self.token_balances.insert(&refund.token_id, &token_balance);
+        }
+
+        refunds
+            .into_iter()
+            .map(|refund| {
+                let promise = Self::internal_transfer_token(
+                    &refund.token_id,
+                    receiver_id,
+                    U128(refund.amount + refund.fees),
+                );
+
+                let callback = ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_ON_TRANSFER_CB)
+                    .with_attached_deposit(0)
+                    .on_withdraw_callback(
+                        account_id.clone(),
+                        receiver_id.clone(),
+                        refund.token_id,
+                        U128(refund.receipts),
+                        U128(refund.amount),
+                        U128(refund.fees),
+                    );
+
+                promise.then(callback)
+            })
+            .reduce(|promises, promise| promises.and(promise))
+    }
+
+    /**
+     * Marks the next tranche as released, it must be approved
+     * Returns the basis points of the funds released up to and including it, None without tranches
+     * If a transfer of the released tranches failed, it is retried first without releasing the next one
+     */
+    fn internal_release_next_tranche(&mut self) -> Option<u32> {
+        if self.metadata.tranches.is_empty() {
+            return None;
+        }
+
+        let released_basis_points: u32 = self
+            .metadata
+            .tranches
+            .iter()
+            .filter(|tranche| tranche.released)
+            .map(|tranche| tranche.basis_points)
+            .sum();
+
+        let has_failed_transfers = self.token_balances.values().any(|token_balance| {
+            token_balance.delegated
+                < Self::calculate_fraction(
+                    token_balance.amount,
+                    released_basis_points,
+                    FEE_BASIS_POINTS_DENOMINATOR,
+                )
+        });
+
+        if has_failed_transfers {
+            return Some(released_basis_points);
+        }
+
+        let index = self
+            .metadata
+            .tranches
+            .iter()
+            .position(|tranche| !tranche.released)
+            .unwrap_or_else(|| env::panic_str("ERR_NOTHING_TO_DELEGATE"));
+
+        let is_proposal_passed = self.is_proposal_passed(Some(index as u32));
+        let tranche = &mut self.metadata.tranches[index];
+
+        if !tranche.approved && !is_proposal_passed {
+            env::panic_str("ERR_TRANCHE_NOT_APPROVED");
+        }
+
+        tranche.approved = true;
+        tranche.released = true;
+
+        TrancheReleasedEvent {
+            index: index as u32,
+            basis_points: tranche.basis_points,
+        }
+        .emit();
+
+        Some(released_basis_points + tranche.basis_points)
+    }
+
+    /**
+     * Whether the last proposal is on tranche, is over, reached the quorum and passed the threshold
+     */
+    pub(crate) fn is_proposal_passed(&self, tranche: Option<u32>) -> bool {
+        let (proposal, voting) = match (&self.proposal, &self.metadata.voting) {
+            (Some(proposal), Some(voting)) => (proposal, voting),
+            _ => return false,
+        };
+
+        let votes_weight = proposal.approve_weight + proposal.reject_weight;
+        let quorum = Self::calculate_fraction(
+            self.ft.total_supply,
+            voting.quorum_basis_points,
+            FEE_BASIS_POINTS_DENOMINATOR,
+        );
+        let threshold = Self::calculate_fraction(
+            votes_weight,
+            voting.threshold_basis_points,
+            FEE_BASIS_POINTS_DENOMINATOR,
+        );
+
+        proposal.tranche == tranche
+            && env::block_timestamp() > proposal.ends_at
+            && votes_weight > 0
+            && votes_weight >= quorum
+            && proposal.approve_weight > threshold
+    }
+
+    /**
+     * Lowers the vote of account_id on the open proposal to its receipt balance
+     * Called whenever receipts leave the account, so each receipt is counted once
+     */
+    fn internal_sync_vote_weight(&mut self, account_id: &AccountId) {
+        let mut proposal = match &self.proposal {
+            Some(proposal) if env::block_timestamp() <= proposal.ends_at => proposal.clone(),
+            _ => return,
+        };
+
+        let mut vote = match self.votes.get(account_id) {
+            Some(vote) if vote.proposal_id == proposal.id => vote,
+            _ => return,
+        };
+
+        let balance = self.ft.accounts.get(account_id).unwrap_or(0);
+
+        if vote.weight <= balance {
+            return;
+        }
+
+        let removed_weight = vote.weight - balance;
+
+        match vote.approve {
+            true => proposal.approve_weight -= removed_weight,
+            false => proposal.reject_weight -= removed_weight,
+        }
+
+        vote.weight = balance;
+        self.votes.insert(account_id, &vote);
+        self.proposal = Some(proposal);
+    }
+
+    /**
+     * Mints the fee of a deposit as receipt tokens to the fee recipients, by weight
+     * Their share of token_fee_amount backs the receipts like a deposit in token_id
+     */
+    fn internal_mint_fees(
+        &mut self,
+        token_id: &AccountId,
+        fee_amount: Balance,
+        token_fee_amount: Balance,
+    ) {
+        for index in 0..self.fees.recipients.len() {
+            let receipts = self.get_fee_share(index, fee_amount);
+
+            if receipts == 0 {
+                continue;
+            }
+
+            let account_id = self.fees.recipients[index].account_id.clone();
+
+            if self.ft.accounts.get(&account_id).is_none() {
+                self.ft.internal_register_account(&account_id);
+            }
+
+            self.internal_before_balance_change(&account_id);
+            self.internal_checkpoint_supply();
+            self.ft.internal_deposit(&account_id, receipts);
+            self.deposits.insert(&account_id);
+
+            let mut contribution = self.contributions.get(&account_id).unwrap_or_default();
+            let token_contribution = contribution.token_mut(token_id);
+            token_contribution.amount += self.get_fee_share(index, token_fee_amount);
+            token_contribution.receipts += receipts;
+            self.contributions.insert(&account_id, &contribution);
+
+            FtMint {
+                owner_id: &account_id,
+                amount: &U128(receipts),
+                memo: Some("fee"),
+            }
+            .emit();
+        }
+    }
+
+    /**
+     * What the escrow owes in token_id and the balance it should hold for it
+     */
+    pub(crate) fn get_token_accounting(&self, token_id: &AccountId) -> TokenAccounting {
+        let token_balance = self.token_balances.get(token_id).unwrap_or_default();
+        let funds = token_balance.amount - token_balance.delegated;
+
+        let platform_amount = self.get_platform_fees(token_id, &token_balance);
+
+        let mut fees_owed: Balance = (0..self.fees.recipients.len())
+            .filter(|index| !self.is_fee_claimed(*index, token_id))
+            .map(|index| self.get_fee_share(index, platform_amount))
+            .sum();
+        let mut obligations = 0;
+
+        if token_id == &self.get_metadata().nep_141 {
+            fees_owed += self
+                .fees
+                .referral_amount
+                .saturating_sub(self.fees.referral_fees_claimed);
+            fees_owed += (0..self.fees.recipients.len())
+                .filter(|index| !self.fees.recipients[*index].sale_claimed)
+                .map(|index| self.get_fee_share(index, self.fees.sale_amount))
+                .sum::<Balance>();
+
+            obligations += self.income.amount - self.income.claimed;
+            // A closed buyback is returning its unused liquidity, it is owed no more
+            obligations += self
+                .buyback
+                .as_ref()
+                .filter(|buyback| !buyback.closed)
+                .map_or(0, |buyback| buyback.liquidity - buyback.paid);
+            obligations += self
+                .settlement
+                .as_ref()
+                .map_or(0, |settlement| settlement.proceeds - settlement.paid);
+        }
+
+        TokenAccounting {
+            token_id: token_id.clone(),
+            held: U128(funds + fees_owed + obligations),
+            funds: U128(funds),
+            fees_owed: U128(fees_owed),
+            obligations: U128(obligations),
+        }
+    }
+
+    /**
+     * Called on ft_on_transfer with an income msg, only from the maintainer and in nep_141
+     * Once the funding succeeded, amount is split among the current receipt holders pro rata
+     * The rounding remainder stays in the escrow
+     */
+    pub(crate) fn internal_deposit_income(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        if sender_id != &self.get_metadata().maintainer_account_id {
+            env::panic_str("ERR_ONLY_MAINTAINER");
+        }
+
+        if token_id != &self.get_metadata().nep_141 {
+            env::panic_str("ERR_WRONG_NEP141");
+        }
+
+        self.assert_status(
+            &[
+                EscrowStatus::Succeeded,
+                EscrowStatus::Delegating,
+                EscrowStatus::Delegated,
+            ],
+            "ERR_INCOME_NOT_ALLOWED",
+        );
+
+        if self.ft.total_supply == 0 {
+            env::panic_str("ERR_NO_RECEIPT_HOLDERS");
+        }
+
+        let amount_per_share = Self::mul_div(
+            amount,
+            PER_SHARE_PRECISION,
+            self.ft.total_supply,
+            "ERR_INCOME_OVERFLOW",
+        );
+
+        self.income.amount_per_share += amount_per_share;
+        self.income.amount += amount;
+
+        IncomeEvent {
+            amount: U128(amount),
+            amount_per_share: U128(self.income.amount_per_share),
+        }
+        .emit();
+    }
+
+    /**
+     * Called on ft_on_transfer with a buyback msg, only from the maintainer and in nep_141
+     * Opens a buyback of the receipt tokens with amount as liquidity, once the funds were delegated
+     */
+    pub(crate) fn internal_deposit_buyback(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        terms: BuybackTerms,
+    ) {
+        if sender_id != &self.get_metadata().maintainer_account_id {
+            env::panic_str("ERR_ONLY_MAINTAINER");
+        }
+
+        if token_id != &self.get_metadata().nep_141 {
+            env::panic_str("ERR_WRONG_NEP141");
+        }
+
+        self.assert_status(
+            &[EscrowStatus::Delegating, EscrowStatus::Delegated],
+            "ERR_BUYBACK_NOT_ALLOWED",
+        );
+
+        if self.buyback.is_some() {
+            env::panic_str("ERR_BUYBACK_ACTIVE");
+        }
+
+        if terms.price_basis_points == 0 || terms.ends_at <= env::block_timestamp() {
+            env::panic_str("ERR_INVALID_BUYBACK");
+        }
+
+        BuybackEvent {
+            price_basis_points: terms.price_basis_points,
+            ends_at: terms.ends_at,
+            liquidity: U128(amount),
+        }
+        .emit();
+
+        self.buyback = Some(Buyback {
+            price_basis_points: terms.price_basis_points,
+            ends_at: terms.ends_at,
+            liquidity: amount,
+            paid: 0,
+            redeemed: 0,
+            closed: false,
+            pending_redeems: 0,
+        });
+    }
+
+    /**
+     * Called on ft_on_transfer with a sale msg, only from the maintainer and in nep_141
+     * Delegated -> Settling, amount is the sale price and fees.sale_basis_points of it goes to the fee recipients
+     * The rest is split among the current receipt holders, who burn their receipts for it on settle
+     */
+    pub(crate) fn internal_deposit_sale(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        if sender_id != &self.get_metadata().maintainer_account_id {
+            env::panic_str("ERR_ONLY_MAINTAINER");
+        }
+
+        if token_id != &self.get_metadata().nep_141 {
+            env::panic_str("ERR_WRONG_NEP141");
+        }
+
+        self.assert_status(&[EscrowStatus::Delegated], "ERR_SALE_NOT_ALLOWED");
+
+        if self.buyback.is_some() {
+            env::panic_str("ERR_BUYBACK_ACTIVE");
+        }
+
+        let supply = self.ft.total_supply;
+
+        if supply == 0 {
+            env::panic_str("ERR_NO_RECEIPT_HOLDERS");
+        }
+
+        let fee = Self::calculate_fraction(
+            amount,
+            self.fees.sale_basis_points,
+            FEE_BASIS_POINTS_DENOMINATOR,
+        );
+        let proceeds = amount - fee;
+        let amount_per_share = Self::mul_div(
+            proceeds,
+            PER_SHARE_PRECISION,
+            supply,
+            "ERR_SETTLEMENT_OVERFLOW",
+        );
+
+        self.fees.sale_amount += fee;
+        self.settlement = Some(Settlement {
+            proceeds,
+            fee,
+            amount_per_share,
+            supply,
+            paid: 0,
+            burned: 0,
+        });
+
+        SaleEvent {
+            proceeds: U128(proceeds),
+            fee: U128(fee),
+        }
+        .emit();
+
+        self.internal_set_status(EscrowStatus::Settling);
+    }
+
+    /**
+     * Income account of account_id settled up to the current Income::amount_per_share, not stored
+     */
+    pub(crate) fn get_income_account(&self, account_id: &AccountId) -> IncomeAccount {
+        let mut income_account = self.income_accounts.get(account_id).unwrap_or_default();
+        let balance = self.ft.accounts.get(account_id).unwrap_or(0);
+
+        income_account.unclaimed += Self::mul_div(
+            balance,
+            self.income.amount_per_share - income_account.amount_per_share_paid,
+            PER_SHARE_PRECISION,
+            "ERR_INCOME_OVERFLOW",
+        );
+        income_account.amount_per_share_paid = self.income.amount_per_share;
+
+        income_account
+    }
+
+    /**
+     * Call it before every change of the receipt balance of account_id
+     * Checkpoints the balance for the last snapshot and settles the income earned with it
+     */
+    pub(crate) fn internal_before_balance_change(&mut self, account_id: &AccountId) {
+        self.internal_checkpoint_balance(account_id);
+
+        if self.income.amount_per_share > 0 {
+            let income_account = self.get_income_account(account_id);
+            self.income_accounts.insert(account_id, &income_account);
+        }
+    }
+
+    /**
+     * Records the receipt balance of account_id for the last snapshot, if it has none yet
+     */
+    pub(crate) fn internal_checkpoint_balance(&mut self, account_id: &AccountId) {
+        let snapshot_id = self.snapshots.len();
+        let mut checkpoints = self.get_balance_checkpoints(account_id);
+
+        if snapshot_id == 0 || Self::last_checkpoint_id(&checkpoints) == Some(snapshot_id) {
+            return;
+        }
+
+        checkpoints.push(&Checkpoint {
+            snapshot_id,
+            balance: self.ft.accounts.get(account_id).unwrap_or(0),
+        });
+        self.balance_checkpoints.insert(account_id, &checkpoints);
+    }
+
+    /**
+     * The checkpoints of account_id live in their own Vector, so a balance change only appends one
+     */
+    pub(crate) fn get_balance_checkpoints(&self, account_id: &AccountId) -> Vector<Checkpoint> {
+        self.balance_checkpoints.get(account_id).unwrap_or_else(|| {
+            Vector::new(StorageKeys::AccountCheckpoints {
+                account_hash: env::sha256(account_id.as_bytes()),
+            })
+        })
+    }
+
+    /**
+     * Records the receipt supply for the last snapshot, if it has none yet
+     * Call it before every mint or burn
+     */
+    pub(crate) fn internal_checkpoint_supply(&mut self) {
+        let snapshot_id = self.snapshots.len();
+
+        if snapshot_id == 0
+            || Self::last_checkpoint_id(&self.supply_checkpoints) == Some(snapshot_id)
+        {
+            return;
+        }
+
+        self.supply_checkpoints.push(&Checkpoint {
+            snapshot_id,
+            balance: self.ft.total_supply,
+        });
+    }
+
+    /**
+     * The value as of snapshot_id is the first checkpoint written after it, or current if there is none
+     * Binary searched in storage, only the visited checkpoints are read
+     */
+    pub(crate) fn checkpoint_at(
+        &self,
+        checkpoints: &Vector<Checkpoint>,
+        snapshot_id: u64,
+        current: Balance,
+    ) -> Balance {
+        if snapshot_id == 0 || snapshot_id > self.snapshots.len() {
+            env::panic_str("ERR_SNAPSHOT_NOT_FOUND");
+        }
+
+        let (mut low, mut high) = (0, checkpoints.len());
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            match checkpoints.get(middle) {
+                Some(checkpoint) if checkpoint.snapshot_id < snapshot_id => low = middle + 1,
+                _ => high = middle,
+            }
+        }
+
+        checkpoints
+            .get(low)
+            .map_or(current, |checkpoint| checkpoint.balance)
+    }
+
+    fn last_checkpoint_id(checkpoints: &Vector<Checkpoint>) -> Option<u64> {
+        checkpoints
+            .len()
+            .checked_sub(1)
+            .and_then(|index| checkpoints.get(index))
+            .map(|checkpoint| checkpoint.snapshot_id)
+    }
+
+    /**
+     * Whether every tranche, if any, is released and every NEP141 fully delegated
+     */
+    pub(crate) fn is_fully_delegated(&self) -> bool {
+        self.metadata
+            .tranches
+            .iter()
+            .all(|tranche| tranche.released)
+            && self
+                .token_balances
+                .values()
+                .all(|token_balance| token_balance.delegated == token_balance.amount)
+    }
+
+    /**
+     * Transfers amount of token_id, native NEAR for NEAR_TOKEN_ID and a NEP141 otherwise
+     */
+    fn internal_transfer_token(
+        token_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: U128,
+    ) -> Promise {
+        if token_id.as_str() == NEAR_TOKEN_ID {
+            return Promise::new(receiver_id.clone()).transfer(amount.0);
+        }
+
+        // NEP141 Transfer
+        ext_ft_core::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_ON_TRANSFER)
+            .ft_transfer(receiver_id.clone(), amount, None)
+    }
+
+    /**
+     * Takes receipts out of the token contributions, in order, with the amount of each NEP141 backing them
+     * Receipts without a token contribution, from escrows migrated before accepted tokens, are backed by nep_141
+     */
+    fn take_token_contributions(
+        &self,
+        contribution: &mut Contribution,
+        receipts: Balance,
+    ) -> Vec<TokenContribution> {
+        let mut taken = vec![];
+        let mut remaining = receipts;
+
+        for token in contribution.tokens.iter_mut() {
+            let receipts = remaining.min(token.receipts);
+
+            if receipts == 0 {
+                continue;
+            }
+
+            let amount = match receipts == token.receipts {
+                true => token.amount,
+                false => self
+                    .to_token_amount(&token.token_id, receipts)
+                    .min(token.amount),
+            };
+
+            token.receipts -= receipts;
+            token.amount -= amount;
+            remaining -= receipts;
+
+            taken.push(TokenContribution {
+                token_id: token.token_id.clone(),
+                amount,
+                fees: 0,
+                receipts,
+            });
+        }
+
+        if remaining > 0 {
+            let nep_141 = self.get_metadata().nep_141;
+
+            match taken.iter_mut().find(|token| token.token_id == nep_141) {
+                Some(token) => {
+                    token.amount += remaining;
+                    token.receipts += remaining;
+                }
+                None => taken.push(TokenContribution {
+                    token_id: nep_141,
+                    amount: remaining,
+                    fees: 0,
+                    receipts: remaining,
+                }),
+            }
+        }
+
+        taken
+    }
+
+    /**
+     * Moves the token contributions backing receipts from sender_id to receiver_id
+     * The receiver joins the deposit accounts, so refund_batch also reaches it
+     */
+    fn internal_transfer_token_contributions(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        receipts: Balance,
+    ) {
+        let mut sender = self.contributions.get(sender_id).unwrap_or_default();
+        let mut receiver = self.contributions.get(receiver_id).unwrap_or_default();
+
+        for token in self.take_token_contributions(&mut sender, receipts) {
+            let receiver_token = receiver.token_mut(&token.token_id);
+            receiver_token.amount += token.amount;
+            receiver_token.receipts += token.receipts;
+        }
+
+        sender
+            .tokens
+            .retain(|token| token.receipts > 0 || token.fees > 0);
+        self.contributions.insert(sender_id, &sender);
+        self.contributions.insert(receiver_id, &receiver);
+        self.deposits.insert(receiver_id);
+    }
+
+    pub(crate) fn assert_not_paused(&self, operation: Operation) {
+        let (paused, err) = match operation {
+            Operation::Deposits => (self.pause.deposits, "ERR_DEPOSITS_PAUSED"),
+            Operation::Withdrawals => (self.pause.withdrawals, "ERR_WITHDRAWALS_PAUSED"),
+            Operation::Delegation => (self.pause.delegation, "ERR_DELEGATION_PAUSED"),
+            Operation::FeeClaims => (self.pause.fee_claims, "ERR_FEE_CLAIMS_PAUSED"),
+        };
+
+        if paused {
+            env::panic_str(err);
+        }
+    }
+
+    fn assert_guardian(&self) {
+        if env::predecessor_account_id() != self.get_guardian_account_id() {
+            env::panic_str("ERR_ONLY_GUARDIAN");
+        }
+    }
+
+    /**
+     * Pauses or unpauses each of operations, emits a pause event for the ones that changed
+     */
+    pub(crate) fn internal_set_paused(&mut self, operations: &[Operation], paused: bool) {
+        let mut changed = vec![];
+
+        for operation in operations {
+            let flag = self.pause.get_mut(*operation);
+
+            if *flag != paused {
+                *flag = paused;
+                changed.push(*operation);
+            }
+        }
+
+        if !changed.is_empty() {
+            PauseEvent {
+                operations: &changed,
+                paused,
+            }
+            .emit();
+        }
+    }
+
+    /**
+     * Stores the current status and panics with err if it is not one of allowed
+     */
+    pub(crate) fn assert_status(&mut self, allowed: &[EscrowStatus], err: &str) {
+        self.internal_set_status(self.get_status());
+
+        if !allowed.contains(&self.status) {
+            env::panic_str(err);
+        }
+    }
+
+    pub(crate) fn internal_set_status(&mut self, status: EscrowStatus) {
+        if self.status != status {
+            self.status = status;
+
+            StatusEvent { status }.emit();
+        }
+    }
+
+    /**
+     * Selects the fee rule of the schedule that applies to this deposit
+     * See Fees for the precedence of the rules
+     */
+    fn get_fee_rule(&self, account_id: &AccountId, amount: Balance) -> (FeeRule, u32) {
+        if let Some(fee) = self
+            .fees
+            .account_overrides
+            .iter()
+            .find(|fee| &fee.account_id == account_id)
+        {
+            return (FeeRule::AccountOverride, fee.basis_points);
+        }
+
+        let mut rule = (FeeRule::Flat, self.fees.basis_points);
+
+        if let Some(fee) = self
+            .fees
+            .volume_tiers
+            .iter()
+            .filter(|fee| fee.min_amount <= amount)
+            .max_by_key(|fee| fee.min_amount)
+        {
+            if fee.basis_points < rule.1 {
+                rule = (FeeRule::VolumeTier, fee.basis_points);
+            }
+        }
+
+        if let Some(fee) = &self.fees.early_bird {
+            if env::block_timestamp() < fee.ends_at && fee.basis_points < rule.1 {
+                rule = (FeeRule::EarlyBird, fee.basis_points);
+            }
+        }
+
+        rule
+    }
+
+    fn internal_record_referral(
+        &mut self,
+        referrer_id: &AccountId,
+        account_id: &AccountId,
+        amount: Balance,
+        fee_amount: Balance,
+    ) {
+        let referral_fee = Self::calculate_fraction(
+            fee_amount,
+            self.fees.referral_basis_points,
+            FEE_BASIS_POINTS_DENOMINATOR,
+        );
+
+        let mut stats = self.referrals.get(referrer_id).unwrap_or_default();
+        stats.deposits_count += 1;
+        stats.amount = stats
+            .amount
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("ERR_REFERRAL_AMOUNT_OVERFLOW"));
+        stats.fees_amount += referral_fee;
+        self.referrals.insert(referrer_id, &stats);
+
+        self.fees.referral_amount += referral_fee;
+
+        ReferralEvent {
+            referrer_id,
+            account_id,
+            amount: U128(amount),
+            fee: U128(referral_fee),
+        }
+        .emit();
+    }
+
+    /**
+     * Share of the platform fees in token_id of the recipient at index, referral fees excluded
+     * The last recipient gets the rounding remainder so the shares add up to the platform fees
+     */
+    fn get_fee_recipient_share(&self, index: usize, token_id: &AccountId) -> Balance {
+        let token_balance = self.token_balances.get(token_id).unwrap_or_default();
+
+        self.get_fee_share(index, self.get_platform_fees(token_id, &token_balance))
+    }
+
+    /**
+     * The fees of token_id shared by the recipients, the referral fees are set aside in nep_141
+     */
+    fn get_platform_fees(&self, token_id: &AccountId, token_balance: &TokenBalance) -> Balance {
+        match self.is_accepted_token(token_id) {
+            true => token_balance.fees,
+            false => token_balance.fees - self.fees.referral_amount,
+        }
+    }
+
+    /**
+     * Share of amount of the recipient at index, the last one also gets the rounding remainder
+     */
+    fn get_fee_share(&self, index: usize, amount: Balance) -> Balance {
+        let recipients = &self.fees.recipients;
+        let total_weight: u32 = recipients.iter().map(|recipient| recipient.weight).sum();
+
+        if index + 1 < recipients.len() {
+            return Self::calculate_fraction(amount, recipients[index].weight, total_weight);
+        }
+
+        let others: Balance = recipients[..index]
+            .iter()
+            .map(|recipient| Self::calculate_fraction(amount, recipient.weight, total_weight))
+            .sum();
+
+        amount - others
+    }
+
+    fn is_fee_claimed(&self, index: usize, token_id: &AccountId) -> bool {
+        let recipient = &self.fees.recipients[index];
+
+        match self.is_accepted_token(token_id) {
+            true => recipient.claimed_tokens.contains(token_id),
+            false => recipient.claimed,
+        }
+    }
+
+    pub(crate) fn internal_set_fee_claimed(
+        &mut self,
+        index: usize,
+        token_id: &AccountId,
+        claimed: bool,
+    ) {
+        let is_accepted_token = self.is_accepted_token(token_id);
+        let recipient = &mut self.fees.recipients[index];
+
+        if !is_accepted_token {
+            recipient.claimed = claimed;
+        } else if claimed {
+            recipient.claimed_tokens.push(token_id.clone());
+        } else {
+            recipient
+                .claimed_tokens
+                .retain(|claimed_token_id| claimed_token_id != token_id);
+        }
+    }
+
+    /**
+     * Whether token_id is one of the accepted tokens, nep_141 is not
+     */
+    pub(crate) fn is_accepted_token(&self, token_id: &AccountId) -> bool {
+        self.get_accepted_token(token_id).is_some()
+    }
+
+    fn get_accepted_token(&self, token_id: &AccountId) -> Option<AcceptedToken> {
+        self.metadata
+            .accepted_tokens
+            .iter()
+            .find(|token| &token.account_id == token_id)
+            .cloned()
+    }
+
+    /**
+     * amount of token_id in nep_141 units, rounded down, see AcceptedToken
+     */
+    pub(crate) fn to_receipt_amount(&self, token_id: &AccountId, amount: Balance) -> Balance {
+        let token = match self.get_accepted_token(token_id) {
+            Some(token) => token,
+            None => return amount,
+        };
+
+        let decimals = self.ft_metadata().decimals;
+        let scale = Balance::pow(10, token.decimals.abs_diff(decimals).into());
+        let amount = match decimals >= token.decimals {
+            true => amount
+                .checked_mul(scale)
+                .unwrap_or_else(|| env::panic_str("ERR_AMOUNT_OVERFLOW")),
+            false => amount / scale,
+        };
+
+        Self::calculate_fraction(
+            amount,
+            token.rate_basis_points,
+            FEE_BASIS_POINTS_DENOMINATOR,
+        )
+    }
+
+    /**
+     * Amount of token_id worth receipt_amount in nep_141 units, rounded up
+     */
+    fn to_token_amount(&self, token_id: &AccountId, receipt_amount: Balance) -> Balance {
+        let token = match self.get_accepted_token(token_id) {
+            Some(token) => token,
+            None => return receipt_amount,
+        };
+
+        let decimals = self.ft_metadata().decimals;
+        let scale = Balance::pow(10, token.decimals.abs_diff(decimals).into());
+        let amount = receipt_amount
+            .checked_mul(FEE_BASIS_POINTS_DENOMINATOR.into())
+            .unwrap_or_else(|| env::panic_str("ERR_AMOUNT_OVERFLOW"))
+            .div_ceil(token.rate_basis_points.into());
+
+        match token.decimals >= decimals {
+            true => amount
+                .checked_mul(scale)
+                .unwrap_or_else(|| env::panic_str("ERR_AMOUNT_OVERFLOW")),
+            false => amount.div_ceil(scale),
+        }
+    }
+
+    /**
+     * amount * numerator / denominator, rounded down
+     * Split in quotient and remainder so the multiplication never overflows u128
+     */
+    fn calculate_fraction(amount: Balance, numerator: u32, denominator: u32) -> Balance {
+        let numerator = Balance::from(numerator);
+        let denominator = Balance::from(denominator);
+
+        (amount / denominator) * numerator + (amount % denominator) * numerator / denominator
+    }
+
+    /**
+     * amount * numerator / denominator, rounded down
+     * The product is computed in 256 bits, panics with err only if the result does not fit u128
+     */
+    fn mul_div(amount: Balance, numerator: Balance, denominator: Balance, err: &str) -> Balance {
+        let result = U256::from(amount) * U256::from(numerator) / U256::from(denominator);
+
+        if result > U256::from(Balance::MAX) {
+            env::panic_str(err);
+        }
+
+        result.as_u128()
+    }
+
+    pub(crate) fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.deposits.insert(&tmp_account_id);
         self.ft.accounts.insert(&tmp_account_id, &0u128);
+        let mut contribution = Contribution::default();
+        contribution.token_mut(&tmp_account_id);
+        self.contributions.insert(&tmp_account_id, &contribution);
+        self.votes.insert(
+            &tmp_account_id,
+            &Vote {
+                proposal_id: 0,
+                approve: false,
+                weight: 0,
+            },
+        );
+        let mut checkpoints = self.get_balance_checkpoints(&tmp_account_id);
+        checkpoints.push(&Checkpoint {
+            snapshot_id: 0,
+            balance: 0,
+        });
+        self.balance_checkpoints
+            .insert(&tmp_account_id, &checkpoints);
+        self.income_accounts
+            .insert(&tmp_account_id, &IncomeAccount::default());
         self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.income_accounts.remove(&tmp_account_id);
+        checkpoints.clear();
+        self.balance_checkpoints.remove(&tmp_account_id);
+        self.votes.remove(&tmp_account_id);
+        self.contributions.remove(&tmp_account_id);
         self.ft.accounts.remove(&tmp_account_id);
         self.deposits.remove(&tmp_account_id);
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Escrow, ft);
+#[near_bindgen]
+impl FungibleTokenCore for Escrow {
+    /**
+     * The token contributions backing the receipts move with them, see TokenContribution
+     * The vote of the sender on the open proposal drops to its new balance
+     * Both balances are checkpointed for the last snapshot and their income settled before they change
+     */
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        let sender_id = env::predecessor_account_id();
+
+        self.internal_before_balance_change(&sender_id);
+        self.internal_before_balance_change(&receiver_id);
+        self.ft.ft_transfer(receiver_id.clone(), amount, memo);
+        self.internal_transfer_token_contributions(&sender_id, &receiver_id, amount.0);
+        self.internal_sync_vote_weight(&sender_id);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let sender_id = env::predecessor_account_id();
+
+        self.internal_before_balance_change(&sender_id);
+        self.internal_before_balance_change(&receiver_id);
+        let promise = self
+            .ft
+            .ft_transfer_call(receiver_id.clone(), amount, memo, msg);
+        self.internal_transfer_token_contributions(&sender_id, &receiver_id, amount.0);
+        self.internal_sync_vote_weight(&sender_id);
+
+        promise
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.ft.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.ft.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Escrow {
+    /**
+     * The receipts returned to sender_id bring their token contributions back
+     */
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        self.internal_before_balance_change(&sender_id);
+        self.internal_before_balance_change(&receiver_id);
+        self.internal_checkpoint_supply();
+
+        let (used_amount, burned_amount) =
+            self.ft
+                .internal_ft_resolve_transfer(&sender_id, receiver_id.clone(), amount);
+        let refunded_amount = amount.0 - used_amount;
+
+        if burned_amount == 0 && refunded_amount > 0 {
+            self.internal_transfer_token_contributions(&receiver_id, &sender_id, refunded_amount);
+            self.internal_sync_vote_weight(&receiver_id);
+        }
+
+        used_amount.into()
+    }
+}
 
 #[near_bindgen]
 impl FungibleTokenMetadataProvider for Escrow {
@@ -0,0 +1,449 @@
+use near_sdk::{env, json_types::U128, serde::Serialize, serde_json, AccountId};
+
+use crate::storage::{EscrowStatus, FeeRule, Operation, Timestamp};
+
+pub const EVENT_STANDARD: &str = "splitfund";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/**
+ * NEP-297 event envelope
+ * Logged as EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"...","data":[...]}
+ */
+#[derive(Serialize)]
+struct SplitfundEvent<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event_kind: EventKind<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+enum EventKind<'a> {
+    Status(&'a [StatusEvent]),
+    Cancel(&'a [CancelEvent]),
+    Deposit(&'a [DepositEvent<'a>]),
+    Referral(&'a [ReferralEvent<'a>]),
+    Withdraw(&'a [WithdrawEvent<'a>]),
+    RefundFailed(&'a [RefundFailedEvent<'a>]),
+    ClaimFees(&'a [ClaimFeesEvent<'a>]),
+    ClaimFeesFailed(&'a [ClaimFeesFailedEvent<'a>]),
+    DelegateFunds(&'a [DelegateFundsEvent<'a>]),
+    TrancheApproved(&'a [TrancheApprovedEvent<'a>]),
+    TrancheReleased(&'a [TrancheReleasedEvent]),
+    Proposal(&'a [ProposalEvent]),
+    Vote(&'a [VoteEvent<'a>]),
+    Snapshot(&'a [SnapshotEvent]),
+    Income(&'a [IncomeEvent]),
+    ClaimIncome(&'a [ClaimIncomeEvent<'a>]),
+    ClaimIncomeFailed(&'a [ClaimIncomeFailedEvent<'a>]),
+    Buyback(&'a [BuybackEvent]),
+    Redeem(&'a [RedeemEvent<'a>]),
+    RedeemFailed(&'a [RedeemFailedEvent<'a>]),
+    BuybackClosed(&'a [BuybackClosedEvent]),
+    BuybackCloseFailed(&'a [BuybackCloseFailedEvent]),
+    Sale(&'a [SaleEvent]),
+    Settle(&'a [SettleEvent<'a>]),
+    SettleFailed(&'a [SettleFailedEvent<'a>]),
+    Discrepancy(&'a [DiscrepancyEvent<'a>]),
+    Pause(&'a [PauseEvent<'a>]),
+    Guardian(&'a [GuardianEvent<'a>]),
+}
+
+impl EventKind<'_> {
+    fn emit(self) {
+        let event = SplitfundEvent {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event_kind: self,
+        };
+
+        let json = serde_json::to_string(&event).unwrap_or_else(|_| env::abort());
+
+        env::log_str(&format!("EVENT_JSON:{}", json));
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatusEvent {
+    pub status: EscrowStatus,
+}
+
+impl StatusEvent {
+    pub fn emit(self) {
+        EventKind::Status(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct DepositEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+    pub fee: U128,
+    pub fee_rule: FeeRule,
+    /// Set only when the deposit was made on behalf of account_id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<&'a AccountId>,
+    /// Set only when the deposit overshot unpaid_amount and the rest was refunded, in the deposited NEP141
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refund: Option<U128>,
+    /// Set only for a deposit in an accepted token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a AccountId>,
+}
+
+impl DepositEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Deposit(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReferralEvent<'a> {
+    pub referrer_id: &'a AccountId,
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+    pub fee: U128,
+}
+
+impl ReferralEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Referral(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct CancelEvent {
+    pub refund_fees: bool,
+}
+
+impl CancelEvent {
+    pub fn emit(self) {
+        EventKind::Cancel(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct WithdrawEvent<'a> {
+    pub account_id: &'a AccountId,
+    /// Set only when the funds were sent to another account
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receiver_id: Option<&'a AccountId>,
+    pub amount: U128,
+    /// Set only when the escrow was cancelled with refundable fees
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees_refund: Option<U128>,
+    /// Set only for an accepted token, amounts are in its own units then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a AccountId>,
+}
+
+impl WithdrawEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Withdraw(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct RefundFailedEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees_refund: Option<U128>,
+    /// Set only for an accepted token, amounts are in its own units then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a AccountId>,
+}
+
+impl RefundFailedEvent<'_> {
+    pub fn emit(self) {
+        EventKind::RefundFailed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClaimFeesEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+    /// Set only for an accepted token, amounts are in its own units then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a AccountId>,
+}
+
+impl ClaimFeesEvent<'_> {
+    pub fn emit(self) {
+        EventKind::ClaimFees(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClaimFeesFailedEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+    /// Set only for an accepted token, amounts are in its own units then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a AccountId>,
+}
+
+impl ClaimFeesFailedEvent<'_> {
+    pub fn emit(self) {
+        EventKind::ClaimFeesFailed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct DelegateFundsEvent<'a> {
+    pub receiver_id: &'a AccountId,
+    pub amount: U128,
+    /// Set only for an accepted token, amounts are in its own units then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a AccountId>,
+}
+
+impl DelegateFundsEvent<'_> {
+    pub fn emit(self) {
+        EventKind::DelegateFunds(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct TrancheApprovedEvent<'a> {
+    pub index: u32,
+    pub milestone: &'a str,
+}
+
+impl TrancheApprovedEvent<'_> {
+    pub fn emit(self) {
+        EventKind::TrancheApproved(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct TrancheReleasedEvent {
+    pub index: u32,
+    pub basis_points: u32,
+}
+
+impl TrancheReleasedEvent {
+    pub fn emit(self) {
+        EventKind::TrancheReleased(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProposalEvent {
+    pub id: u64,
+    /// Not set for the whole delegation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tranche: Option<u32>,
+    pub ends_at: Timestamp,
+}
+
+impl ProposalEvent {
+    pub fn emit(self) {
+        EventKind::Proposal(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct VoteEvent<'a> {
+    pub proposal_id: u64,
+    pub account_id: &'a AccountId,
+    pub approve: bool,
+    pub weight: U128,
+}
+
+impl VoteEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Vote(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct SnapshotEvent {
+    pub id: u64,
+    pub taken_at: Timestamp,
+}
+
+impl SnapshotEvent {
+    pub fn emit(self) {
+        EventKind::Snapshot(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct IncomeEvent {
+    pub amount: U128,
+    /// Income of one receipt unit since the first deposit, scaled by PER_SHARE_PRECISION
+    pub amount_per_share: U128,
+}
+
+impl IncomeEvent {
+    pub fn emit(self) {
+        EventKind::Income(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClaimIncomeEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+}
+
+impl ClaimIncomeEvent<'_> {
+    pub fn emit(self) {
+        EventKind::ClaimIncome(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClaimIncomeFailedEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+}
+
+impl ClaimIncomeFailedEvent<'_> {
+    pub fn emit(self) {
+        EventKind::ClaimIncomeFailed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct BuybackEvent {
+    pub price_basis_points: u32,
+    pub ends_at: Timestamp,
+    pub liquidity: U128,
+}
+
+impl BuybackEvent {
+    pub fn emit(self) {
+        EventKind::Buyback(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct RedeemEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub receipts: U128,
+    pub amount: U128,
+}
+
+impl RedeemEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Redeem(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct RedeemFailedEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub receipts: U128,
+    pub amount: U128,
+}
+
+impl RedeemFailedEvent<'_> {
+    pub fn emit(self) {
+        EventKind::RedeemFailed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct BuybackClosedEvent {
+    /// Unused liquidity returned to the maintainer
+    pub amount: U128,
+}
+
+impl BuybackClosedEvent {
+    pub fn emit(self) {
+        EventKind::BuybackClosed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct BuybackCloseFailedEvent {
+    pub amount: U128,
+}
+
+impl BuybackCloseFailedEvent {
+    pub fn emit(self) {
+        EventKind::BuybackCloseFailed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct SaleEvent {
+    pub proceeds: U128,
+    pub fee: U128,
+}
+
+impl SaleEvent {
+    pub fn emit(self) {
+        EventKind::Sale(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct SettleEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub receipts: U128,
+    pub amount: U128,
+}
+
+impl SettleEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Settle(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct SettleFailedEvent<'a> {
+    pub account_id: &'a AccountId,
+    pub receipts: U128,
+    pub amount: U128,
+}
+
+impl SettleFailedEvent<'_> {
+    pub fn emit(self) {
+        EventKind::SettleFailed(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct DiscrepancyEvent<'a> {
+    pub token_id: &'a AccountId,
+    /// Balance the escrow should hold according to its ledger
+    pub expected: U128,
+    /// Balance reported by the NEP141
+    pub actual: U128,
+}
+
+impl DiscrepancyEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Discrepancy(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct PauseEvent<'a> {
+    pub operations: &'a [Operation],
+    pub paused: bool,
+}
+
+impl PauseEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Pause(&[self]).emit()
+    }
+}
+
+#[derive(Serialize)]
+pub struct GuardianEvent<'a> {
+    pub guardian_account_id: &'a AccountId,
+    /// Set while the new guardian has not accepted the role yet
+    pub pending: bool,
+}
+
+impl GuardianEvent<'_> {
+    pub fn emit(self) {
+        EventKind::Guardian(&[self]).emit()
+    }
+}
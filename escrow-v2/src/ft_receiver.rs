@@ -1,12 +1,17 @@
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
-use near_sdk::{env, json_types::U128, near_bindgen, AccountId, PromiseOrValue};
+use near_sdk::{env, json_types::U128, near_bindgen, serde_json, AccountId, PromiseOrValue};
 
 use crate::*;
 
 #[near_bindgen]
 impl FungibleTokenReceiver for Escrow {
-    // `msg` doesn't affect the implementation for ft_on_transfer.
-    #[allow(unused_variables)]
+    /**
+     * msg is an optional JSON DepositInstruction
+     * The predecessor must be nep_141 or one of the accepted NEP141
+     * A msg with income set is rent for the receipt holders, see internal_deposit_income
+     * A msg with buyback set is liquidity to buy back receipt tokens, see internal_deposit_buyback
+     * A msg with sale set is the proceeds of the property sale, see internal_deposit_sale
+     */
     #[payable]
     fn ft_on_transfer(
         &mut self,
@@ -14,14 +19,45 @@ impl FungibleTokenReceiver for Escrow {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        if env::predecessor_account_id() != self.get_metadata().nep_141 {
+        self.assert_not_paused(Operation::Deposits);
+
+        let token_id = env::predecessor_account_id();
+
+        // NEAR_TOKEN_ID is a real account, native NEAR only comes through deposit_near
+        if token_id.as_str() == NEAR_TOKEN_ID
+            || (token_id != self.get_metadata().nep_141 && !self.is_accepted_token(&token_id))
+        {
             env::panic_str("ERR_WRONG_NEP141");
         }
 
         assert!(amount.0 > 0, "ERR_ZERO_AMOUNT");
 
-        self.deposit(sender_id, amount.0);
+        let instruction: DepositInstruction = if msg.is_empty() {
+            DepositInstruction::default()
+        } else {
+            serde_json::from_str(&msg).unwrap_or_else(|_| env::panic_str("ERR_INVALID_MSG"))
+        };
+
+        if instruction.income {
+            self.internal_deposit_income(&sender_id, &token_id, amount.0);
+
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if let Some(terms) = instruction.buyback {
+            self.internal_deposit_buyback(&sender_id, &token_id, amount.0, terms);
+
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if instruction.sale {
+            self.internal_deposit_sale(&sender_id, &token_id, amount.0);
+
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let refund = self.deposit(sender_id, token_id, amount.0, instruction);
 
-        PromiseOrValue::Value(U128(0))
+        PromiseOrValue::Value(U128(refund))
     }
 }
@@ -13,9 +13,15 @@ pub use views::*;
 pub mod callbacks;
 pub use callbacks::*;
 
+pub mod events;
+pub use events::*;
+
 pub mod ft_receiver;
 pub use ft_receiver::*;
 
+pub mod migrations;
+pub use migrations::*;
+
 pub mod escrow;
 pub use crate::escrow::*;
 
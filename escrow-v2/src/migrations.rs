@@ -0,0 +1,145 @@
+use near_contract_standards::fungible_token::{metadata::FungibleTokenMetadata, FungibleToken};
+use near_sdk::{
+    borsh::{self, BorshDeserialize, BorshSerialize},
+    collections::{LazyOption, LookupMap, UnorderedMap, UnorderedSet, Vector},
+    env, near_bindgen, AccountId, Balance, StorageUsage,
+};
+
+use crate::consts::*;
+use crate::storage::*;
+
+/**
+ * Layout of escrows deployed with the f32 Fees::percentage
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct EscrowF32Fees {
+    pub deposits: UnorderedSet<AccountId>,
+    pub ft: FungibleToken,
+    pub ft_metadata: LazyOption<FungibleTokenMetadata>,
+    pub metadata: MetadataF32Fees,
+    pub fees: FeesF32,
+    pub account_storage_usage: StorageUsage,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MetadataF32Fees {
+    pub expires_at: Timestamp,
+    pub funding_amount_limit: u128,
+    pub unpaid_amount: u128,
+    pub nep_141: AccountId,
+    pub maintainer_account_id: AccountId,
+    pub metadata_url: String,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FeesF32 {
+    pub percentage: f32,
+    pub amount: Balance,
+    pub account_id: AccountId,
+    pub claimed: bool,
+}
+
+#[near_bindgen]
+impl Escrow {
+    /**
+     * Converts the f32 Fees::percentage of an already deployed escrow to basis points
+     * Call it right after deploying the new code on the escrow account
+     * The funds raised so far are recorded as the nep_141 token balance
+     * total_supply started at funding_amount_limit before any deposit, it is brought back to the minted receipts
+     * account_storage_usage is measured again, accounts store contributions, votes, checkpoints and income now
+     */
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: EscrowF32Fees =
+            env::state_read().unwrap_or_else(|| env::panic_str("ERR_NO_STATE_TO_MIGRATE"));
+
+        let basis_points = (old.fees.percentage * FEE_BASIS_POINTS_DENOMINATOR as f32).round();
+
+        if !(0.0..=FEE_BASIS_POINTS_DENOMINATOR as f32).contains(&basis_points) {
+            env::panic_str("ERR_INVALID_FEE_BASIS_POINTS");
+        }
+
+        let funds_amount = old.metadata.funding_amount_limit - old.metadata.unpaid_amount;
+        let mut token_balances = UnorderedMap::new(StorageKeys::TokenBalances);
+        token_balances.insert(
+            &old.metadata.nep_141,
+            &TokenBalance {
+                amount: funds_amount.saturating_sub(old.fees.amount),
+                fees: old.fees.amount,
+                delegated: 0,
+            },
+        );
+
+        let mut ft = old.ft;
+        ft.total_supply = ft
+            .total_supply
+            .saturating_sub(old.metadata.funding_amount_limit);
+
+        let mut this = Self {
+            deposits: old.deposits,
+            ft,
+            ft_metadata: old.ft_metadata,
+            metadata: Metadata {
+                expires_at: old.metadata.expires_at,
+                funding_amount_limit: old.metadata.funding_amount_limit,
+                unpaid_amount: old.metadata.unpaid_amount,
+                nep_141: old.metadata.nep_141,
+                maintainer_account_id: old.metadata.maintainer_account_id,
+                metadata_url: old.metadata.metadata_url,
+                min_deposit: None,
+                max_per_account: None,
+                soft_cap: None,
+                accepted_tokens: vec![],
+                tranches: vec![],
+                approver_account_id: None,
+                voting: None,
+                snapshot_interval: None,
+                pause_on_discrepancy: false,
+                guardian_account_id: None,
+            },
+            fees: Fees {
+                basis_points: basis_points as u32,
+                early_bird: None,
+                volume_tiers: vec![],
+                account_overrides: vec![],
+                amount: old.fees.amount,
+                recipients: vec![FeeRecipient {
+                    account_id: old.fees.account_id,
+                    weight: 1,
+                    claimed: old.fees.claimed,
+                    claimed_tokens: vec![],
+                    sale_claimed: false,
+                }],
+                referral_basis_points: 0,
+                referral_amount: 0,
+                referral_fees_claimed: 0,
+                refundable: false,
+                sale_basis_points: 0,
+                sale_amount: 0,
+                mint_policy: FeeMintPolicy::NoMint,
+            },
+            account_storage_usage: 0,
+            referrals: UnorderedMap::new(StorageKeys::Referrals),
+            contributions: UnorderedMap::new(StorageKeys::Contributions),
+            status: EscrowStatus::Funding,
+            refund_failures: UnorderedSet::new(StorageKeys::RefundFailures),
+            token_balances,
+            proposal: None,
+            votes: UnorderedMap::new(StorageKeys::Votes),
+            snapshots: Vector::new(StorageKeys::Snapshots),
+            balance_checkpoints: LookupMap::new(StorageKeys::BalanceCheckpoints),
+            supply_checkpoints: Vector::new(StorageKeys::SupplyCheckpoints),
+            income: Income::default(),
+            income_accounts: LookupMap::new(StorageKeys::IncomeAccounts),
+            buyback: None,
+            settlement: None,
+            pause: Pause::default(),
+            pending_guardian_account_id: None,
+        };
+
+        this.measure_account_storage_usage();
+
+        this
+    }
+}
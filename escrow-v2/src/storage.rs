@@ -1,7 +1,8 @@
 use near_contract_standards::fungible_token::{metadata::FungibleTokenMetadata, FungibleToken};
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::{LazyOption, UnorderedSet},
+    collections::{LazyOption, LookupMap, UnorderedMap, UnorderedSet, Vector},
+    json_types::U128,
     near_bindgen,
     serde::{Deserialize, Serialize},
     AccountId, Balance, BorshStorageKey, StorageUsage,
@@ -18,6 +19,46 @@ pub struct Escrow {
     pub metadata: Metadata,
     pub fees: Fees,
     pub account_storage_usage: StorageUsage,
+    pub referrals: UnorderedMap<AccountId, ReferralStats>,
+    pub contributions: UnorderedMap<AccountId, Contribution>,
+    pub status: EscrowStatus,
+    pub refund_failures: UnorderedSet<AccountId>,
+    pub token_balances: UnorderedMap<AccountId, TokenBalance>,
+    pub proposal: Option<Proposal>,
+    pub votes: UnorderedMap<AccountId, Vote>,
+    pub snapshots: Vector<Snapshot>,
+    pub balance_checkpoints: LookupMap<AccountId, Vector<Checkpoint>>,
+    pub supply_checkpoints: Vector<Checkpoint>,
+    pub income: Income,
+    pub income_accounts: LookupMap<AccountId, IncomeAccount>,
+    pub buyback: Option<Buyback>,
+    pub settlement: Option<Settlement>,
+    pub pause: Pause,
+    pub pending_guardian_account_id: Option<AccountId>,
+}
+
+/**
+ * Funding -> Succeeded, when funding_amount_limit is reached or the escrow expires with the soft cap reached
+ * Funding -> Failed, when the escrow expires otherwise
+ * Succeeded -> Delegating -> Delegated, on delegate_funds, back to Succeeded if the transfer fails
+ *   or while tranches remain to be released
+ * Funding | Succeeded -> Cancelled, on cancel by the maintainer, a refundable state like Failed
+ * Delegated -> Settling, when the maintainer deposits the proceeds of the property sale
+ * Settling -> Closed, once every receipt token was burned for its share of the proceeds
+ */
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum EscrowStatus {
+    Funding,
+    Succeeded,
+    Failed,
+    Delegating,
+    Delegated,
+    Cancelled,
+    Settling,
+    Closed,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
@@ -28,14 +69,460 @@ pub struct Metadata {
     pub nep_141: AccountId,
     pub maintainer_account_id: AccountId,
     pub metadata_url: String,
+    // Minimum total contribution of an account, waived for the deposit that completes the funding
+    #[serde(default)]
+    pub min_deposit: Option<u128>,
+    // Maximum total contribution of an account, the excess of a deposit is refunded
+    #[serde(default)]
+    pub max_per_account: Option<u128>,
+    // If set, the funding also succeeds when the escrow expires with at least soft_cap raised
+    #[serde(default)]
+    pub soft_cap: Option<u128>,
+    // NEP141 accepted besides nep_141, amounts and limits are always in nep_141 units
+    #[serde(default)]
+    pub accepted_tokens: Vec<AcceptedToken>,
+    // If set, delegate_funds releases the funds tranche by tranche, each approved by approver_account_id
+    #[serde(default)]
+    pub tranches: Vec<Tranche>,
+    #[serde(default)]
+    pub approver_account_id: Option<AccountId>,
+    // If set, each tranche, or the whole delegation, may also be approved by a vote of the receipt holders
+    #[serde(default)]
+    pub voting: Option<VotingPolicy>,
+    // If set, anyone may take a snapshot once snapshot_interval nanoseconds passed since the last one
+    #[serde(default)]
+    pub snapshot_interval: Option<u64>,
+    // If set, a reconcile that finds less than the ledger in a NEP141 pauses the escrow
+    #[serde(default)]
+    pub pause_on_discrepancy: bool,
+    // Pauses and unpauses the escrow, the maintainer if not set
+    #[serde(default)]
+    pub guardian_account_id: Option<AccountId>,
+}
+
+/**
+ * Operations the guardian pauses independently, each paused method fails with its error:
+ * Deposits: ft_on_transfer and deposit_near, ERR_DEPOSITS_PAUSED
+ * Withdrawals: withdraw, refund_batch, claim_income, redeem, close_buyback and settle, ERR_WITHDRAWALS_PAUSED
+ * Delegation: delegate_funds, ERR_DELEGATION_PAUSED
+ * FeeClaims: claim_fees and claim_referral_fees, ERR_FEE_CLAIMS_PAUSED
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Deposits,
+    Withdrawals,
+    Delegation,
+    FeeClaims,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+pub struct Pause {
+    pub deposits: bool,
+    pub withdrawals: bool,
+    pub delegation: bool,
+    pub fee_claims: bool,
+}
+
+impl Pause {
+    pub fn get_mut(&mut self, operation: Operation) -> &mut bool {
+        match operation {
+            Operation::Deposits => &mut self.deposits,
+            Operation::Withdrawals => &mut self.withdrawals,
+            Operation::Delegation => &mut self.delegation,
+            Operation::FeeClaims => &mut self.fee_claims,
+        }
+    }
+}
+
+/**
+ * A proposal passes once voting_period is over if the votes cast reach quorum_basis_points of the receipt supply
+ * and the approvals are more than threshold_basis_points of the votes cast
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct VotingPolicy {
+    pub quorum_basis_points: u32,
+    pub threshold_basis_points: u32,
+    pub voting_period: u64,
+}
+
+/**
+ * Approval of the release of tranche, or of the whole delegation without tranches
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Proposal {
+    pub id: u64,
+    pub tranche: Option<u32>,
+    pub ends_at: Timestamp,
+    pub approve_weight: Balance,
+    pub reject_weight: Balance,
 }
 
+/**
+ * weight is the receipt balance of the voter, lowered if the balance drops before the proposal ends
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Vote {
+    pub proposal_id: u64,
+    pub approve: bool,
+    pub weight: Balance,
+}
+
+/**
+ * Rent paid by the maintainer in nep_141, split among the receipt holders pro rata
+ * amount_per_share is the income of one receipt unit since the first deposit, scaled by PER_SHARE_PRECISION
+ * amount is the total income deposited, claimed the part of it paid out
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+pub struct Income {
+    pub amount_per_share: u128,
+    pub amount: Balance,
+    pub claimed: Balance,
+}
+
+/**
+ * unclaimed is the income of the account up to amount_per_share_paid
+ * Settled before every change of its receipt balance, so transfers do not move past income
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+pub struct IncomeAccount {
+    pub amount_per_share_paid: u128,
+    pub unclaimed: Balance,
+}
+
+/**
+ * Liquidity in nep_141 the maintainer offers for the receipt tokens until ends_at
+ * Each receipt unit redeemed pays price_basis_points / 10_000 nep_141 units, rounded down
+ * paid is the liquidity paid out for redeemed receipts, the rest returns to the maintainer on close_buyback
+ * closed is set while it is being returned, the buyback is removed once it was
+ * pending_redeems counts the redeem transfers not settled yet, close_buyback waits for them
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Buyback {
+    pub price_basis_points: u32,
+    pub ends_at: Timestamp,
+    pub liquidity: Balance,
+    pub paid: Balance,
+    pub redeemed: Balance,
+    pub closed: bool,
+    pub pending_redeems: u64,
+}
+
+/**
+ * Proceeds of the property sale in nep_141, net of the sale fee, paid out as the holders burn their receipts
+ * amount_per_share is the proceeds of one receipt unit, scaled by PER_SHARE_PRECISION
+ * supply is the receipt supply at the sale, the last receipts burned also get the rounding remainder
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Settlement {
+    pub proceeds: Balance,
+    pub fee: Balance,
+    pub amount_per_share: u128,
+    pub supply: Balance,
+    pub paid: Balance,
+    pub burned: Balance,
+}
+
+/**
+ * Terms of a buyback, sent by the maintainer in the msg of the liquidity transfer
+ */
+#[derive(Serialize, Deserialize)]
+pub struct BuybackTerms {
+    pub price_basis_points: u32,
+    pub ends_at: Timestamp,
+}
+
+/**
+ * Receipt balances as of taken_at are read with balance_of_at(account_id, id)
+ * Snapshot ids start at 1
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub id: u64,
+    pub taken_at: Timestamp,
+}
+
+/**
+ * Receipt balance of an account, or the receipt supply, as of snapshot_id
+ * Written lazily on the first balance change after the snapshot, ordered by snapshot_id
+ * A missing checkpoint means the balance did not change since
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub snapshot_id: u64,
+    pub balance: Balance,
+}
+
+/**
+ * basis_points of the funds of each NEP141, released on delegate_funds once approved
+ * milestone describes the work the tranche pays for, or is the hash of its metadata_url document
+ * Tranches are released in order, their basis_points add up to 10_000
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct Tranche {
+    pub basis_points: u32,
+    pub milestone: String,
+    #[serde(default)]
+    pub approved: bool,
+    #[serde(default)]
+    pub released: bool,
+}
+
+/**
+ * A deposit of amount is worth, in nep_141 units and receipt tokens:
+ * amount * 10^(receipt decimals) / 10^decimals * rate_basis_points / 10_000, rounded down
+ * nep_141 itself is worth 1:1 and shares the decimals of the receipt token
+ * Native NEAR is accepted as account_id NEAR_TOKEN_ID with 24 decimals, deposited through deposit_near
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct AcceptedToken {
+    pub account_id: AccountId,
+    pub decimals: u8,
+    pub rate_basis_points: u32,
+}
+
+/**
+ * Funds held in one NEP141, in its own units
+ * amount is what investors deposited net of fees, delegated the part of it released on delegate_funds
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+pub struct TokenBalance {
+    pub amount: Balance,
+    pub fees: Balance,
+    pub delegated: Balance,
+}
+
+/**
+ * Fees are charged in basis points: 1 bp = 0.01%, 10_000 bps = 100%
+ * The fee of a deposit is rounded down to the nearest unit of the NEP141
+ *
+ * Fee schedule, the applicable rule of a deposit is:
+ * 1. the account override of the depositor, if any
+ * 2. otherwise the lowest of basis_points, the early bird (before ends_at)
+ *    and the highest volume tier whose min_amount is <= the deposit amount
+ *
+ * amount is the total fee collected, referral_amount is the part of it owed to referrers
+ * referral_fees_claimed is the part of referral_amount claimed so far, across all referrers
+ * referral_basis_points is the share of the fee of a referred deposit that goes to its referrer
+ * refundable is set on cancel, then withdraw also returns the fees paid by the account
+ *
+ * Fees are collected in the NEP141 of each deposit, amount is in nep_141 units
+ * Only deposits in nep_141 earn referral fees
+ * mint_policy decides whether the fee is claimed in tokens or minted as receipt tokens to the recipients
+ */
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
 pub struct Fees {
-    pub percentage: f32,
+    pub basis_points: u32,
+    #[serde(default)]
+    pub early_bird: Option<EarlyBirdFee>,
+    #[serde(default)]
+    pub volume_tiers: Vec<VolumeTierFee>,
+    #[serde(default)]
+    pub account_overrides: Vec<AccountFee>,
     pub amount: Balance,
+    pub recipients: Vec<FeeRecipient>,
+    #[serde(default)]
+    pub referral_basis_points: u32,
+    #[serde(default)]
+    pub referral_amount: Balance,
+    #[serde(default)]
+    pub referral_fees_claimed: Balance,
+    #[serde(default)]
+    pub refundable: bool,
+    // Charged on the sale proceeds, sale_amount is the fee collected in nep_141
+    #[serde(default)]
+    pub sale_basis_points: u32,
+    #[serde(default)]
+    pub sale_amount: Balance,
+    #[serde(default)]
+    pub mint_policy: FeeMintPolicy,
+}
+
+/**
+ * NoMint: the fee of a deposit is set aside in its NEP141 and paid out on claim_fees
+ * MintToRecipients: the fee stays in the escrow funds, the recipients get receipt tokens for it by weight
+ *   backed by the fee like any deposit, so the receipt supply matches the funds raised
+ *   Referral fees are not supported, and cancel refund_fees does not apply to these receipts
+ */
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Debug,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeMintPolicy {
+    #[default]
+    NoMint,
+    MintToRecipients,
+}
+
+/**
+ * Each recipient claims amount * weight / total weight, rounded down
+ * The last recipient also gets the rounding remainder
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct FeeRecipient {
     pub account_id: AccountId,
+    pub weight: u32,
+    #[serde(default)]
     pub claimed: bool,
+    // Accepted tokens whose fees were claimed, claimed covers nep_141
+    #[serde(default)]
+    pub claimed_tokens: Vec<AccountId>,
+    #[serde(default)]
+    pub sale_claimed: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct EarlyBirdFee {
+    pub ends_at: Timestamp,
+    pub basis_points: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct VolumeTierFee {
+    pub min_amount: Balance,
+    pub basis_points: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct AccountFee {
+    pub account_id: AccountId,
+    pub basis_points: u32,
+}
+
+/**
+ * Progress of refund_batch, the next batch starts from next_index
+ * refunds is the number of refunds sent
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RefundBatch {
+    pub refunds: u64,
+    pub next_index: u64,
+}
+
+/**
+ * Ledger of the escrow, see get_accounting_report
+ * total_funds is what was raised and not refunded, in nep_141 units
+ * backed_supply is the part of total_supply backed by token contributions, only receipts of migrated escrows are not
+ */
+#[derive(Serialize, Deserialize)]
+pub struct AccountingReport {
+    pub total_supply: U128,
+    pub backed_supply: U128,
+    pub total_funds: U128,
+    pub fees_amount: U128,
+    pub fee_mint_policy: FeeMintPolicy,
+    pub tokens: Vec<TokenAccounting>,
+    pub balanced: bool,
+}
+
+/**
+ * held is the balance the escrow should hold in token_id: funds + fees_owed + obligations
+ * funds are the deposits net of fees and not delegated yet, fees_owed the fees not claimed yet
+ * obligations are the income, buyback liquidity and sale proceeds not paid out yet, in nep_141 only
+ */
+#[derive(Serialize, Deserialize)]
+pub struct TokenAccounting {
+    pub token_id: AccountId,
+    pub held: U128,
+    pub funds: U128,
+    pub fees_owed: U128,
+    pub obligations: U128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeRule {
+    Flat,
+    EarlyBird,
+    VolumeTier,
+    AccountOverride,
+}
+
+/**
+ * Total deposited by an account and the fees it paid, in nep_141 units
+ * tokens backs the receipt tokens of the account, in the NEP141 it was paid with
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+pub struct Contribution {
+    pub amount: Balance,
+    pub fees: Balance,
+    #[serde(default)]
+    pub tokens: Vec<TokenContribution>,
+}
+
+impl Contribution {
+    pub fn token_mut(&mut self, token_id: &AccountId) -> &mut TokenContribution {
+        let index = match self
+            .tokens
+            .iter()
+            .position(|token| &token.token_id == token_id)
+        {
+            Some(index) => index,
+            None => {
+                self.tokens.push(TokenContribution {
+                    token_id: token_id.clone(),
+                    amount: 0,
+                    fees: 0,
+                    receipts: 0,
+                });
+                self.tokens.len() - 1
+            }
+        };
+
+        &mut self.tokens[index]
+    }
+}
+
+/**
+ * receipts of the account are refunded with amount of token_id, net of fees
+ * receipts and amount move with the receipt tokens on ft_transfer, the fees stay with the payer
+ */
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+pub struct TokenContribution {
+    pub token_id: AccountId,
+    pub amount: Balance,
+    pub fees: Balance,
+    pub receipts: Balance,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+pub struct ReferralStats {
+    pub deposits_count: u64,
+    pub amount: Balance,
+    pub fees_amount: Balance,
+    pub fees_claimed: Balance,
+}
+
+/**
+ * JSON msg of ft_transfer_call, an empty msg is a plain deposit
+ * beneficiary_id gets the receipt tokens and the refund rights instead of the sender
+ * Unknown fields are rejected, so a misspelled referrer_id does not go unnoticed
+ */
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DepositInstruction {
+    #[serde(default)]
+    pub referrer_id: Option<AccountId>,
+    #[serde(default)]
+    pub beneficiary_id: Option<AccountId>,
+    // Rent from the maintainer for the receipt holders, not an investment
+    #[serde(default)]
+    pub income: bool,
+    // Liquidity from the maintainer to buy back receipt tokens
+    #[serde(default)]
+    pub buyback: Option<BuybackTerms>,
+    // Proceeds of the property sale from the maintainer, settles the escrow
+    #[serde(default)]
+    pub sale: bool,
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -43,4 +530,14 @@ pub enum StorageKeys {
     Deposits,
     FungibleToken,
     FungibleTokenMetadata,
+    Referrals,
+    Contributions,
+    RefundFailures,
+    TokenBalances,
+    Votes,
+    Snapshots,
+    BalanceCheckpoints,
+    AccountCheckpoints { account_hash: Vec<u8> },
+    SupplyCheckpoints,
+    IncomeAccounts,
 }
@@ -68,8 +68,22 @@ impl StorageManagement for Escrow {
         let account_id = env::predecessor_account_id();
         if let Some(balance) = self.ft.accounts.get(&account_id) {
             if balance == 0 {
+                // Contributions and failed refunds are paid back to the account, it must stay registered
+                let has_contributions = self
+                    .contributions
+                    .get(&account_id)
+                    .is_some_and(|contribution| !contribution.tokens.is_empty());
+
+                if has_contributions || self.refund_failures.contains(&account_id) {
+                    env::panic_str("ERR_CONTRIBUTIONS_NOT_REFUNDED");
+                }
+
                 self.ft.accounts.remove(&account_id);
-                self.deposits.remove(&account_id);
+
+                // The deposit accounts keep their order while refund_batch pages through them
+                if !self.is_withdrawal_allowed() {
+                    self.deposits.remove(&account_id);
+                }
                 Promise::new(account_id.clone()).transfer(self.storage_balance_bounds().min.0 + 1);
                 true
             } else {
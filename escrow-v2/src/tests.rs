@@ -5,18 +5,20 @@ mod tests {
     use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
     use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
     use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::PromiseOrValue::Value;
     use near_sdk::PromiseResult;
     use near_sdk::{
         json_types::U128,
         test_utils::{
-            accounts,
+            accounts, get_logs,
             test_env::{alice, bob},
             VMContextBuilder,
         },
         testing_env, AccountId, Balance,
     };
-    //use near_sdk::PromiseOrValue::Value;
 
+    use crate::consts::*;
+    use crate::migrations::*;
     use crate::storage::*;
 
     const ATTACHED_DEPOSIT: Balance = 1_000_000_000_000_000_000_000_000; // 1 Near
@@ -31,6 +33,16 @@ mod tests {
         builder
     }
 
+    fn set_promise_result(context: &VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
     fn nep_141_account_id() -> AccountId {
         AccountId::new_unchecked("nep141.near".to_string())
     }
@@ -59,17 +71,42 @@ mod tests {
             nep_141,
             maintainer_account_id: maintainer,
             metadata_url: "".to_string(),
+            min_deposit: None,
+            max_per_account: None,
+            soft_cap: None,
+            accepted_tokens: vec![],
+            tranches: vec![],
+            approver_account_id: None,
+            voting: None,
+            snapshot_interval: None,
+            pause_on_discrepancy: false,
+            guardian_account_id: None,
         }
     }
 
-    fn new_fees(percentage: f32, account_id: Option<AccountId>) -> Fees {
+    fn new_fees(basis_points: u32, account_id: Option<AccountId>) -> Fees {
         let account_id = account_id.unwrap_or(fees_account_id());
 
         Fees {
-            percentage,
+            basis_points,
+            early_bird: None,
+            volume_tiers: vec![],
+            account_overrides: vec![],
             amount: 0,
-            account_id,
-            claimed: false,
+            recipients: vec![FeeRecipient {
+                account_id,
+                weight: 1,
+                claimed: false,
+                claimed_tokens: vec![],
+                sale_claimed: false,
+            }],
+            referral_basis_points: 0,
+            referral_amount: 0,
+            referral_fees_claimed: 0,
+            refundable: false,
+            sale_basis_points: 0,
+            sale_amount: 0,
+            mint_policy: FeeMintPolicy::NoMint,
         }
     }
 
@@ -87,11 +124,12 @@ mod tests {
 
     fn setup_contract(expires_at: u64, funding_amount_limit: u128) -> Escrow {
         let metadata = new_metadata(expires_at, funding_amount_limit, None, None);
-        let fees = new_fees(0.03, None);
-        let ft_metadata = new_ft_metadata("sa1".to_string(), 4);
 
-        let contract = Escrow::new(metadata, fees, ft_metadata, None);
-        contract
+        setup_contract_with(metadata, new_fees(300, None))
+    }
+
+    fn setup_contract_with(metadata: Metadata, fees: Fees) -> Escrow {
+        Escrow::new(metadata, fees, new_ft_metadata("sa1".to_string(), 4), None)
     }
 
     fn register_account(contract: &mut Escrow, account: AccountId) {
@@ -113,6 +151,66 @@ mod tests {
         Escrow::default();
     }
 
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_FEE_BASIS_POINTS")]
+    fn new_invalid_fee_basis_points_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+
+        setup_contract_with(metadata, new_fees(10_001, None));
+    }
+
+    #[test]
+    fn migrate_f32_fees_to_basis_points() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+
+        let contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+        let account_storage_usage = contract.account_storage_usage;
+
+        // Escrows deployed with the f32 fees started total_supply at funding_amount_limit
+        let mut ft = contract.ft;
+        ft.total_supply = MIN_FUNDING_AMOUNT;
+
+        let old = EscrowF32Fees {
+            deposits: contract.deposits,
+            ft,
+            ft_metadata: contract.ft_metadata,
+            metadata: MetadataF32Fees {
+                expires_at: contract.metadata.expires_at,
+                funding_amount_limit: contract.metadata.funding_amount_limit,
+                unpaid_amount: contract.metadata.unpaid_amount,
+                nep_141: contract.metadata.nep_141,
+                maintainer_account_id: contract.metadata.maintainer_account_id,
+                metadata_url: contract.metadata.metadata_url,
+            },
+            fees: FeesF32 {
+                percentage: 0.03,
+                amount: 1_234,
+                account_id: fees_account_id(),
+                claimed: false,
+            },
+            // Only the deposit and the receipt balance of an account were measured
+            account_storage_usage: 200,
+        };
+
+        near_sdk::env::state_write(&old);
+
+        let contract = Escrow::migrate();
+        let fees = contract.get_fees();
+
+        assert_eq!(300, fees.basis_points);
+        assert_eq!(1_234, fees.amount);
+        assert_eq!(fees_account_id(), fees.recipients[0].account_id);
+        assert!(!fees.recipients[0].claimed);
+        assert_eq!(MIN_FUNDING_AMOUNT, contract.get_metadata().unpaid_amount);
+        assert!(contract.get_metadata().max_per_account.is_none());
+        assert_eq!(0, contract.ft_total_supply().0);
+        assert_eq!(account_storage_usage, contract.account_storage_usage);
+    }
+
     //################
     // Test On Deposit
 
@@ -158,8 +256,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "ERR_AMOUNT_GT_UNPAID_AMOUNT")]
-    fn deposit_amount_gt_unpaid_amount() {
+    fn deposit_amount_gt_unpaid_amount_partial_fill() {
         let context = get_context(nep_141_account_id());
         testing_env!(context.build());
 
@@ -176,11 +273,25 @@ mod tests {
 
         contract.ft_on_transfer(bob(), U128(bob_investment), "".to_string());
 
-        // Bob Deposit x2
+        // Bob Deposit x2, only 100_000 is unpaid
         let context = get_context(nep_141_account_id());
         testing_env!(context.build());
 
-        contract.ft_on_transfer(bob(), U128(bob_investment), "".to_string());
+        let refund = contract.ft_on_transfer(bob(), U128(bob_investment), "".to_string());
+
+        match refund {
+            Value(refund) => assert_eq!(800_000, refund.0),
+            _ => panic!("Expected a refund value"),
+        }
+
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"bob.near","amount":"97000","fee":"3000","fee_rule":"flat","refund":"800000"}]}"#
+        );
+
+        assert_eq!(0, contract.get_metadata().unpaid_amount);
+        assert_eq!(970_000, contract.ft.ft_balance_of(bob()).0);
+        assert!(contract.is_funding_reached());
     }
 
     #[test]
@@ -211,6 +322,14 @@ mod tests {
 
         contract.ft_on_transfer(alice(), U128(alice_investment), "".to_string());
 
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{"owner_id":"alice.near","amount":"43650"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"alice.near","amount":"43650","fee":"1350","fee_rule":"flat"}]}"#,
+            ]
+        );
+
         // Check balances
 
         let amount_bob = contract.ft.ft_balance_of(bob());
@@ -224,81 +343,3804 @@ mod tests {
         );
     }
 
-    //#################
-    // Test On Withdraw
+    #[test]
+    fn deposit_fee_rounds_down_without_precision_loss() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        // 24 decimals, well above the f32 mantissa
+        let one_near: Balance = 1_000_000_000_000_000_000_000_000;
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, 100_000_000 * one_near);
+
+        let bob_investment = 16_777_217 * one_near + 33;
+
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(bob_investment), "".to_string());
+
+        // 3% of 33 yocto is 0.99, rounded down to 0
+        let expected_fee = 16_777_217 * one_near / 10_000 * 300;
+
+        assert_eq!(expected_fee, contract.get_fees().amount);
+        assert_eq!(
+            bob_investment - expected_fee,
+            contract.ft.ft_balance_of(bob()).0
+        );
+    }
 
     #[test]
-    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
-    fn withdraw_1yocto_error() {
+    fn deposit_fee_schedule_early_bird() {
         let context = get_context(nep_141_account_id());
         testing_env!(context.build());
 
         let expires_at = add_expires_at_nanos(100);
-        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+        let mut fees = new_fees(300, None);
+        fees.early_bird = Some(EarlyBirdFee {
+            ends_at: 10,
+            basis_points: 100,
+        });
+        let mut contract = setup_contract_with(
+            new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
 
-        contract.withdraw();
+        register_account(&mut contract, bob());
+        register_account(&mut contract, alice());
+
+        // Bob deposits before the early bird ends
+        let mut context = get_context(nep_141_account_id());
+        testing_env!(context.block_timestamp(5).build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"bob.near","amount":"99000","fee":"1000","fee_rule":"early_bird"}]}"#
+        );
+
+        // Alice deposits after the early bird ends
+        let mut context = get_context(nep_141_account_id());
+        testing_env!(context.block_timestamp(10).build());
+
+        contract.ft_on_transfer(alice(), U128(100_000), "".to_string());
+
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"alice.near","amount":"97000","fee":"3000","fee_rule":"flat"}]}"#
+        );
     }
 
     #[test]
-    #[should_panic(expected = "ERR_WITHDRAWAL_NOT_ALLOWED")]
-    fn withdraw_not_allowed_error() {
+    fn deposit_fee_schedule_volume_tiers() {
         let context = get_context(nep_141_account_id());
         testing_env!(context.build());
 
         let expires_at = add_expires_at_nanos(100);
-        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+        let mut fees = new_fees(300, None);
+        fees.volume_tiers = vec![
+            VolumeTierFee {
+                min_amount: 200_000,
+                basis_points: 100,
+            },
+            VolumeTierFee {
+                min_amount: 100_000,
+                basis_points: 200,
+            },
+        ];
+        let mut contract = setup_contract_with(
+            new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
 
-        let mut context = get_context(bob());
+        register_account(&mut contract, bob());
 
-        testing_env!(context.attached_deposit(1).build());
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
 
-        contract.withdraw();
+        contract.ft_on_transfer(bob(), U128(50_000), "".to_string());
+        contract.ft_on_transfer(bob(), U128(150_000), "".to_string());
+        contract.ft_on_transfer(bob(), U128(250_000), "".to_string());
+
+        assert_eq!(1_500 + 3_000 + 2_500, contract.get_fees().amount);
+        assert!(get_logs()[5].contains(r#""fee":"2500","fee_rule":"volume_tier""#));
     }
 
     #[test]
-    fn withdraw_success() {
+    fn deposit_fee_schedule_account_override() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut fees = new_fees(300, None);
+        fees.early_bird = Some(EarlyBirdFee {
+            ends_at: expires_at,
+            basis_points: 100,
+        });
+        fees.account_overrides = vec![AccountFee {
+            account_id: bob(),
+            basis_points: 150,
+        }];
+        let mut contract = setup_contract_with(
+            new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        assert_eq!(1_500, contract.get_fees().amount);
+        assert!(get_logs()[1].contains(r#""fee":"1500","fee_rule":"account_override""#));
+        assert_eq!(1, contract.get_fees().account_overrides.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_AMOUNT_LT_MIN_DEPOSIT")]
+    fn deposit_lt_min_deposit_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with(
+            Metadata {
+                min_deposit: Some(50_000),
+                ..new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+
+        contract.ft_on_transfer(bob(), U128(49_999), "".to_string());
+    }
+
+    #[test]
+    fn deposit_min_deposit_is_cumulative() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with(
+            Metadata {
+                min_deposit: Some(50_000),
+                ..new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+
+        assert_eq!(Some(50_000), contract.get_metadata().min_deposit);
+
+        contract.ft_on_transfer(bob(), U128(50_000), "".to_string());
+        contract.ft_on_transfer(bob(), U128(10), "".to_string());
+
+        assert_eq!(50_010, contract.get_contribution(bob()));
+
+        // The deposit that completes the funding may be lower than min_deposit
+        contract.ft_on_transfer(alice(), U128(900_000), "".to_string());
+        contract.ft_on_transfer(accounts(2), U128(49_990), "".to_string());
+
+        assert!(contract.is_funding_reached());
+    }
+
+    #[test]
+    fn deposit_max_per_account_partial_fill() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with(
+            Metadata {
+                max_per_account: Some(100_000),
+                ..new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+
+        assert_eq!(Some(100_000), contract.get_metadata().max_per_account);
+
+        contract.ft_on_transfer(bob(), U128(60_000), "".to_string());
+
+        let refund = contract.ft_on_transfer(bob(), U128(60_000), "".to_string());
+
+        match refund {
+            Value(refund) => assert_eq!(20_000, refund.0),
+            _ => panic!("Expected a refund value"),
+        }
+
+        assert_eq!(100_000, contract.get_contribution(bob()));
+        assert_eq!(900_000, contract.get_metadata().unpaid_amount);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_PER_ACCOUNT_REACHED")]
+    fn deposit_max_per_account_reached_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with(
+            Metadata {
+                max_per_account: Some(100_000),
+                ..new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+        contract.ft_on_transfer(bob(), U128(1), "".to_string());
+    }
+
+    #[test]
+    fn deposit_on_behalf_of_unregistered_beneficiary() {
         let context = get_context(nep_141_account_id());
         testing_env!(context.build());
 
         let expires_at = add_expires_at_nanos(100);
         let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
 
-        // Bob Deposit
-        let bob_investment = 100_000;
+        let beneficiary_id = accounts(2);
 
-        register_account(&mut contract, bob());
+        assert!(contract
+            .storage_balance_of(beneficiary_id.clone())
+            .is_none());
 
+        // Bob funds a position for the beneficiary
         let context = get_context(nep_141_account_id());
         testing_env!(context.build());
 
-        contract.ft_on_transfer(bob(), U128(bob_investment), "".to_string());
+        let msg = format!(r#"{{"beneficiary_id":"{}"}}"#, beneficiary_id);
 
-        let amount_bob = contract.ft.ft_balance_of(bob());
+        contract.ft_on_transfer(bob(), U128(100_000), msg);
 
-        assert_eq!((bob_investment as f32 * 0.97) as u128, amount_bob.0);
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{"owner_id":"charlie","amount":"97000"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"charlie","amount":"97000","fee":"3000","fee_rule":"flat","sender_id":"bob.near"}]}"#,
+            ]
+        );
 
-        // Bob Withdraw
-        register_account(&mut contract, bob());
+        assert_eq!(97_000, contract.ft.ft_balance_of(beneficiary_id.clone()).0);
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+        assert!(contract
+            .storage_balance_of(beneficiary_id.clone())
+            .is_some());
+        assert!(contract
+            .get_deposit_accounts()
+            .contains(&beneficiary_id.to_string()));
+        assert!(!contract.get_deposit_accounts().contains(&bob().to_string()));
 
-        let mut context = get_context(bob());
+        // The beneficiary withdraws, not the payer
+        let mut context = get_context(beneficiary_id.clone());
 
         testing_env!(context
             .block_timestamp(expires_at + 1000)
             .attached_deposit(1)
             .build());
 
-        contract.withdraw();
+        contract.withdraw(None);
 
-        testing_env!(
-            context.build(),
-            near_sdk::VMConfig::test(),
-            near_sdk::RuntimeFeesConfig::test(),
-            Default::default(),
-            vec![PromiseResult::Successful(vec![])],
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_withdraw_callback(
+            beneficiary_id.clone(),
+            beneficiary_id.clone(),
+            nep_141_account_id(),
+            U128(97_000),
+            U128(97_000),
+            U128(0),
         );
 
-        contract.on_withdraw_callback(bob(), amount_bob);
+        assert_eq!(0, contract.ft.ft_balance_of(beneficiary_id).0);
+    }
 
-        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_REFERRER")]
+    fn deposit_beneficiary_self_referral_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let msg = r#"{"referrer_id":"alice.near","beneficiary_id":"alice.near"}"#.to_string();
+
+        contract.ft_on_transfer(bob(), U128(100_000), msg);
+    }
+
+    //#################
+    // Test On Referral
+
+    #[test]
+    fn deposit_with_referrer() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut fees = new_fees(300, None);
+        fees.referral_basis_points = 2_000;
+        let mut contract = setup_contract_with(
+            new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let msg = r#"{"referrer_id":"alice.near"}"#.to_string();
+
+        contract.ft_on_transfer(bob(), U128(100_000), msg.clone());
+
+        assert_eq!(
+            get_logs()[2],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"referral","data":[{"referrer_id":"alice.near","account_id":"bob.near","amount":"100000","fee":"600"}]}"#
+        );
+
+        contract.ft_on_transfer(bob(), U128(50_000), msg);
+
+        let stats = contract.get_referral_stats(alice()).unwrap();
+
+        assert_eq!(2, stats.deposits_count);
+        assert_eq!(150_000, stats.amount);
+        assert_eq!(600 + 300, stats.fees_amount);
+        assert_eq!(0, stats.fees_claimed);
+        assert_eq!(4_500, contract.get_fees().amount);
+        assert_eq!(900, contract.get_fees().referral_amount);
+        assert!(contract.get_referral_stats(bob()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_MSG")]
+    fn deposit_invalid_msg_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "alice.near".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_MSG")]
+    fn deposit_misspelled_msg_field_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let msg = r#"{"referer_id":"alice.near"}"#.to_string();
+
+        contract.ft_on_transfer(bob(), U128(100_000), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_REFERRER")]
+    fn deposit_self_referral_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let msg = r#"{"referrer_id":"bob.near"}"#.to_string();
+
+        contract.ft_on_transfer(bob(), U128(100_000), msg);
+    }
+
+    #[test]
+    fn claim_referral_fees_success() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut fees = new_fees(300, None);
+        fees.referral_basis_points = 5_000;
+        let mut contract = setup_contract_with(
+            new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        // Bob Deposit referred by Alice, reaches funding_amount_limit
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let msg = r#"{"referrer_id":"alice.near"}"#.to_string();
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), msg);
+
+        // Alice claims her half of the fee, the transfer fails
+        let mut context = get_context(alice());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_referral_fees();
+
+        assert_eq!(
+            15_000,
+            contract.get_referral_stats(alice()).unwrap().fees_claimed
+        );
+        assert_eq!(15_000, contract.get_fees().referral_fees_claimed);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        contract.on_claim_referral_fees_callback(alice(), U128(15_000));
+
+        assert_eq!(
+            0,
+            contract.get_referral_stats(alice()).unwrap().fees_claimed
+        );
+        assert_eq!(0, contract.get_fees().referral_fees_claimed);
+
+        // Alice claims again
+        let mut context = get_context(alice());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_referral_fees();
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_claim_referral_fees_callback(alice(), U128(15_000));
+
+        assert_eq!(
+            15_000,
+            contract.get_referral_stats(alice()).unwrap().fees_claimed
+        );
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"claim_fees","data":[{"account_id":"alice.near","amount":"15000"}]}"#,
+            ]
+        );
+    }
+
+    //#################
+    // Test On Withdraw
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn withdraw_1yocto_error() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.withdraw(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWAL_NOT_ALLOWED")]
+    fn withdraw_not_allowed_error() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let mut context = get_context(bob());
+
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.withdraw(None);
+    }
+
+    #[test]
+    fn withdraw_success() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        // Bob Deposit
+        let bob_investment = 100_000;
+
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(bob_investment), "".to_string());
+
+        let amount_bob = contract.ft.ft_balance_of(bob());
+
+        assert_eq!(bob_investment * 9_700 / 10_000, amount_bob.0);
+
+        // Bob Withdraw
+        register_account(&mut contract, bob());
+
+        let mut context = get_context(bob());
+
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            nep_141_account_id(),
+            amount_bob,
+            amount_bob,
+            U128(0),
+        );
+
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"bob.near","amount":"97000"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"withdraw","data":[{"account_id":"bob.near","amount":"97000"}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOTHING_TO_WITHDRAW")]
+    fn withdraw_concurrent_receipts_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        // Two withdraw receipts in the same block, before any callback
+        let mut context = get_context(bob());
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+
+        contract.withdraw(None);
+    }
+
+    #[test]
+    fn withdraw_failed_transfer_restores_balance() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let mut context = get_context(bob());
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert_eq!(
+            0,
+            contract.on_withdraw_callback(
+                bob(),
+                bob(),
+                nep_141_account_id(),
+                U128(97_000),
+                U128(97_000),
+                U128(0)
+            )
+        );
+        assert_eq!(97_000, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(900_000, contract.get_metadata().unpaid_amount);
+
+        // Bob withdraws again
+        testing_env!(context.build());
+
+        contract.withdraw(None);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert_eq!(
+            97_000,
+            contract.on_withdraw_callback(
+                bob(),
+                bob(),
+                nep_141_account_id(),
+                U128(97_000),
+                U128(97_000),
+                U128(0)
+            )
+        );
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(997_000, contract.get_metadata().unpaid_amount);
+        assert!(contract.get_token_contributions(bob()).is_empty());
+    }
+
+    #[test]
+    fn withdraw_by_predecessor_to_receiver() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let dao_account_id = AccountId::new_unchecked("dao.sputnik.near".to_string());
+
+        contract.ft_on_transfer(dao_account_id.clone(), U128(100_000), "".to_string());
+
+        // A DAO proposal executed by bob: bob signs, the DAO is the predecessor
+        let mut context = get_context(dao_account_id.clone());
+        testing_env!(context
+            .signer_account_id(bob())
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(Some(alice()));
+
+        assert_eq!(0, contract.ft.ft_balance_of(dao_account_id.clone()).0);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_withdraw_callback(
+            dao_account_id,
+            alice(),
+            nep_141_account_id(),
+            U128(97_000),
+            U128(97_000),
+            U128(0),
+        );
+
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"withdraw","data":[{"account_id":"dao.sputnik.near","receiver_id":"alice.near","amount":"97000"}]}"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOTHING_TO_WITHDRAW")]
+    fn withdraw_ignores_signer_balance() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        // A relayer calls on behalf of bob, the relayer has nothing deposited
+        let mut context = get_context(accounts(3));
+        testing_env!(context
+            .signer_account_id(bob())
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+    }
+
+    //###################
+    // Test On Soft Cap
+
+    #[test]
+    fn soft_cap_reached_after_expiration_delegates_raised_funds() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with(
+            Metadata {
+                soft_cap: Some(500_000),
+                ..new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+
+        contract.ft_on_transfer(bob(), U128(400_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(200_000), "".to_string());
+
+        assert!(contract.is_soft_cap_reached());
+        assert_eq!(582_000, contract.ft.ft_total_supply().0);
+
+        let mut context = get_context(maintainer_account_id());
+
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        assert!(!contract.is_deposit_allowed());
+        assert!(!contract.is_withdrawal_allowed());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"succeeded"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"delegating"}]}"#,
+            ]
+        );
+        assert_eq!(EscrowStatus::Delegating, contract.get_status());
+    }
+
+    #[test]
+    fn soft_cap_not_reached_after_expiration_allows_withdrawal() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with(
+            Metadata {
+                soft_cap: Some(500_000),
+                ..new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+
+        contract.ft_on_transfer(bob(), U128(400_000), "".to_string());
+
+        assert!(!contract.is_soft_cap_reached());
+
+        let mut context = get_context(bob());
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert!(contract.is_withdrawal_allowed());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_SOFT_CAP")]
+    fn new_soft_cap_gt_funding_amount_limit_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        setup_contract_with(
+            Metadata {
+                soft_cap: Some(MIN_FUNDING_AMOUNT + 1),
+                ..new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None)
+            },
+            new_fees(300, None),
+        );
+    }
+
+    //######################
+    // Test On Refund Batch
+
+    #[test]
+    fn refund_batch_survives_failed_transfers() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(50_000), "".to_string());
+
+        // Anyone pushes the refunds after the round failed
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        // maintainer.near has nothing to refund
+        assert_eq!(
+            RefundBatch {
+                refunds: 2,
+                next_index: 3
+            },
+            contract.refund_batch(0, 10)
+        );
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(0, contract.ft.ft_balance_of(alice()).0);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert_eq!(
+            97_000,
+            contract.on_withdraw_callback(
+                bob(),
+                bob(),
+                nep_141_account_id(),
+                U128(97_000),
+                U128(97_000),
+                U128(0)
+            )
+        );
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert_eq!(
+            0,
+            contract.on_withdraw_callback(
+                alice(),
+                alice(),
+                nep_141_account_id(),
+                U128(48_500),
+                U128(48_500),
+                U128(0)
+            )
+        );
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"refund_failed","data":[{"account_id":"alice.near","amount":"48500"}]}"#,
+            ]
+        );
+        assert_eq!(48_500, contract.ft.ft_balance_of(alice()).0);
+        assert_eq!(vec![alice().to_string()], contract.get_refund_failures());
+
+        // The next batch skips alice, anyone retries her refund
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert_eq!(
+            RefundBatch {
+                refunds: 0,
+                next_index: 3
+            },
+            contract.refund_batch(0, 10)
+        );
+
+        contract.retry_refund(alice());
+
+        assert_eq!(vec![alice().to_string()], contract.get_refund_failures());
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert_eq!(
+            48_500,
+            contract.on_withdraw_callback(
+                alice(),
+                alice(),
+                nep_141_account_id(),
+                U128(48_500),
+                U128(48_500),
+                U128(0)
+            )
+        );
+        assert!(contract.get_refund_failures().is_empty());
+        assert_eq!(0, contract.ft.ft_total_supply().0);
+    }
+
+    #[test]
+    fn refund_batch_paginates() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(50_000), "".to_string());
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert_eq!(
+            RefundBatch {
+                refunds: 1,
+                next_index: 2
+            },
+            contract.refund_batch(0, 2)
+        );
+
+        // Bob is refunded and keeps his place, alice is still on the second page
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            nep_141_account_id(),
+            U128(97_000),
+            U128(97_000),
+            U128(0),
+        );
+
+        assert_eq!(
+            vec![
+                maintainer_account_id().to_string(),
+                bob().to_string(),
+                alice().to_string()
+            ],
+            contract.get_deposit_accounts()
+        );
+
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert_eq!(
+            RefundBatch {
+                refunds: 1,
+                next_index: 3
+            },
+            contract.refund_batch(2, 2)
+        );
+        assert_eq!(
+            RefundBatch {
+                refunds: 0,
+                next_index: 4
+            },
+            contract.refund_batch(4, 2)
+        );
+    }
+
+    #[test]
+    fn refund_batch_stops_when_gas_runs_low() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(50_000), "".to_string());
+
+        // Only one refund transfer fits, alice is left for the next batch
+        let mut context = get_context(accounts(3));
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .prepaid_gas(GAS_PER_REFUND_TRANSFER + GAS_PER_REFUND_TRANSFER / 2)
+            .build());
+
+        assert_eq!(
+            RefundBatch {
+                refunds: 1,
+                next_index: 2
+            },
+            contract.refund_batch(0, 10)
+        );
+        assert_eq!(48_500, contract.ft.ft_balance_of(alice()).0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn refund_batch_not_enough_gas_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .prepaid_gas(GAS_PER_REFUND_TRANSFER / 2)
+            .build());
+
+        contract.refund_batch(1, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_LIMIT")]
+    fn refund_batch_limit_gt_max_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        contract.refund_batch(0, REFUND_BATCH_MAX_LIMIT + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_REFUND_FAILURE")]
+    fn retry_refund_without_failure_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        contract.retry_refund(bob());
+    }
+
+    #[test]
+    fn refund_failed_after_unregister_registers_again() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let mut context = get_context(bob());
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert_eq!(
+            RefundBatch {
+                refunds: 1,
+                next_index: 2
+            },
+            contract.refund_batch(0, 10)
+        );
+
+        // Bob unregisters while his refund is pending
+        testing_env!(context.attached_deposit(1).build());
+
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(bob()).is_none());
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert_eq!(
+            0,
+            contract.on_withdraw_callback(
+                bob(),
+                bob(),
+                nep_141_account_id(),
+                U128(97_000),
+                U128(97_000),
+                U128(0)
+            )
+        );
+        assert_eq!(97_000, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(vec![bob().to_string()], contract.get_refund_failures());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_CONTRIBUTIONS_NOT_REFUNDED")]
+    fn storage_unregister_with_contributions_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        register_account(&mut contract, alice());
+
+        // Bob keeps the fees he paid, they are refunded if the round fails
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.ft_transfer(alice(), U128(97_000), None);
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REFUND_NOT_ALLOWED")]
+    fn refund_batch_while_funding_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        contract.refund_batch(0, 10);
+    }
+
+    //###############
+    // Test On Cancel
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_MAINTAINER")]
+    fn cancel_not_maintainer_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(bob());
+        testing_env!(context.build());
+
+        contract.cancel(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSIT_NOT_ALLOWED")]
+    fn cancel_rejects_deposits() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.cancel(None);
+
+        assert_eq!(EscrowStatus::Cancelled, contract.get_status());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_CANCEL_NOT_ALLOWED")]
+    fn cancel_after_delegation_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+        contract.cancel(None);
+    }
+
+    #[test]
+    fn cancel_allows_withdraw_before_expiration() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.cancel(None);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"cancelled"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"cancel","data":[{"refund_fees":false}]}"#,
+            ]
+        );
+
+        // Bob withdraws before expires_at, the fees are kept
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.withdraw(None);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        let amount = contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            nep_141_account_id(),
+            U128(97_000),
+            U128(97_000),
+            U128(0),
+        );
+
+        assert_eq!(97_000, amount);
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(3_000, contract.get_fees().amount);
+    }
+
+    #[test]
+    fn cancel_with_refundable_fees() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(50_000), "".to_string());
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.cancel(Some(true));
+
+        assert!(contract.get_fees().refundable);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.withdraw(None);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        let amount = contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            nep_141_account_id(),
+            U128(97_000),
+            U128(97_000),
+            U128(3_000),
+        );
+
+        assert_eq!(100_000, amount);
+        assert_eq!(1_500, contract.get_fees().amount);
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"withdraw","data":[{"account_id":"bob.near","amount":"97000","fees_refund":"3000"}]}"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEES_REFUNDABLE")]
+    fn cancel_with_refundable_fees_rejects_claim_fees() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.cancel(Some(true));
+
+        let mut context = get_context(fees_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+    }
+
+    //###################
+    // Test On Claim Fees
+
+    #[test]
+    fn claim_fees_success() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        // Bob Deposit, reaches funding_amount_limit
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let fees_amount = U128(contract.get_fees().amount);
+
+        // Claim Fees
+        let mut context = get_context(fees_account_id());
+
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_claim_fees_callback(fees_account_id(), nep_141_account_id(), fees_amount);
+
+        assert!(contract.get_fees().recipients[0].claimed);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"claim_fees","data":[{"account_id":"fees.near","amount":"30000"}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn claim_fees_multiple_recipients_partial_failure() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut fees = new_fees(300, None);
+        fees.recipients.push(FeeRecipient {
+            account_id: alice(),
+            weight: 2,
+            claimed: false,
+            claimed_tokens: vec![],
+            sale_claimed: false,
+        });
+        let mut contract = setup_contract_with(
+            new_metadata(expires_at, MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        // Bob Deposit, reaches funding_amount_limit
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        // 30_000 split 1:2
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+
+        assert!(contract.get_fees().recipients[0].claimed);
+        assert!(contract.get_fees().recipients[1].claimed);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_claim_fees_callback(fees_account_id(), nep_141_account_id(), U128(10_000));
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_claim_fees_callback(alice(), nep_141_account_id(), U128(20_000)));
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"claim_fees_failed","data":[{"account_id":"alice.near","amount":"20000"}]}"#,
+            ]
+        );
+
+        assert!(contract.get_fees().recipients[0].claimed);
+        assert!(!contract.get_fees().recipients[1].claimed);
+
+        // Only alice is claimed again
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+
+        assert!(contract.get_fees().recipients[1].claimed);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEES_ALREADY_CLAIMED")]
+    fn claim_fees_twice_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+        contract.claim_fees();
+    }
+
+    //########################
+    // Test On Delegate Funds
+
+    #[test]
+    fn delegate_funds_success() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        // Bob Deposit, reaches funding_amount_limit
+        register_account(&mut contract, bob());
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        // Delegate Funds
+        let mut context = get_context(maintainer_account_id());
+
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(EscrowStatus::Delegating, contract.get_status());
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(970_000),
+        );
+
+        assert_eq!(EscrowStatus::Delegated, contract.get_status());
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"delegated"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"delegate_funds","data":[{"receiver_id":"maintainer.near","amount":"970000"}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn delegate_funds_failed_transfer_back_to_succeeded() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(970_000)
+        ));
+        assert_eq!(EscrowStatus::Succeeded, contract.get_status());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DELEGATE_NOT_ALLOWED")]
+    fn delegate_funds_twice_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+        contract.delegate_funds(None);
+    }
+
+    //#################
+    // Test On Status
+
+    #[test]
+    fn status_transitions() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        assert_eq!(EscrowStatus::Funding, contract.get_status());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        assert_eq!(EscrowStatus::Funding, contract.get_status());
+
+        // Expired without reaching the funding amount
+        let mut context = get_context(bob());
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert_eq!(EscrowStatus::Failed, contract.get_status());
+        assert!(contract.is_withdrawal_allowed());
+        assert!(!contract.is_deposit_allowed());
+    }
+
+    #[test]
+    fn status_succeeded_on_funding_reached() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        assert_eq!(EscrowStatus::Succeeded, contract.status);
+        assert_eq!(
+            get_logs()[2],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"succeeded"}]}"#
+        );
+
+        // Stays Succeeded after the expiration date
+        let mut context = get_context(bob());
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        assert_eq!(EscrowStatus::Succeeded, contract.get_status());
+    }
+
+    fn dai_account_id() -> AccountId {
+        AccountId::new_unchecked("dai.near".to_string())
+    }
+
+    fn usdc_account_id() -> AccountId {
+        AccountId::new_unchecked("usdc.near".to_string())
+    }
+
+    const DAI: Balance = 1_000_000_000_000_000_000;
+
+    fn setup_contract_with_accepted_tokens(expires_at: u64, funding_amount_limit: u128) -> Escrow {
+        let mut metadata = new_metadata(expires_at, funding_amount_limit, None, None);
+        metadata.accepted_tokens = vec![
+            AcceptedToken {
+                account_id: dai_account_id(),
+                decimals: 18,
+                rate_basis_points: 10_000,
+            },
+            AcceptedToken {
+                account_id: usdc_account_id(),
+                decimals: 6,
+                rate_basis_points: 10_000,
+            },
+        ];
+
+        setup_contract_with(metadata, new_fees(300, None))
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_ACCEPTED_TOKENS")]
+    fn new_accepted_token_is_nep_141_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.accepted_tokens = vec![AcceptedToken {
+            account_id: nep_141_account_id(),
+            decimals: 4,
+            rate_basis_points: 10_000,
+        }];
+
+        setup_contract_with(metadata, new_fees(300, None));
+    }
+
+    #[test]
+    fn deposit_accepted_token_normalizes_decimals() {
+        let context = get_context(dai_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        // 10 DAI, 18 decimals, are 100_000 units of the 4 decimals receipt
+        contract.ft_on_transfer(bob(), U128(10 * DAI), "".to_string());
+
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"bob.near","amount":"97000","fee":"3000","fee_rule":"flat","token_id":"dai.near"}]}"#
+        );
+
+        assert_eq!(97_000, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(100_000, contract.get_total_funds());
+        assert_eq!(3_000, contract.get_fees().amount);
+
+        let (token_id, token_balance) = &contract.get_token_balances()[0];
+        assert_eq!(&dai_account_id(), token_id);
+        assert_eq!(97 * DAI / 10, token_balance.amount);
+        assert_eq!(3 * DAI / 10, token_balance.fees);
+
+        let token_contributions = contract.get_token_contributions(bob());
+        assert_eq!(1, token_contributions.len());
+        assert_eq!(97 * DAI / 10, token_contributions[0].amount);
+        assert_eq!(97_000, token_contributions[0].receipts);
+    }
+
+    #[test]
+    fn deposit_accepted_token_partial_fill_refunds_token_units() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(alice(), U128(950_000), "".to_string());
+
+        // 10 USDC, 6 decimals, are 100_000 units but only 50_000 are unpaid
+        let context = get_context(usdc_account_id());
+        testing_env!(context.build());
+
+        let refund = contract.ft_on_transfer(bob(), U128(10_000_000), "".to_string());
+
+        match refund {
+            Value(refund) => assert_eq!(5_000_000, refund.0),
+            _ => panic!("Expected a refund value"),
+        }
+
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"bob.near","amount":"48500","fee":"1500","fee_rule":"flat","refund":"5000000","token_id":"usdc.near"}]}"#
+        );
+        assert!(contract.is_funding_reached());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_NEP141")]
+    fn deposit_token_not_accepted_err() {
+        let context = get_context(AccountId::new_unchecked("usdt.near".to_string()));
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+    }
+
+    #[test]
+    fn withdraw_returns_each_deposited_token() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let context = get_context(dai_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(10 * DAI), "".to_string());
+
+        assert_eq!(194_000, contract.ft.ft_balance_of(bob()).0);
+
+        // Bob Withdraw, one transfer per token
+        let mut context = get_context(bob());
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+
+        assert_eq!(0, contract.ft.ft_balance_of(bob()).0);
+        assert!(contract.get_token_contributions(bob()).is_empty());
+
+        // The nep_141 transfer succeeds, the DAI transfer fails
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            nep_141_account_id(),
+            U128(97_000),
+            U128(97_000),
+            U128(0),
+        );
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            dai_account_id(),
+            U128(97_000),
+            U128(97 * DAI / 10),
+            U128(0),
+        );
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"refund_failed","data":[{"account_id":"bob.near","amount":"9700000000000000000","token_id":"dai.near"}]}"#,
+            ]
+        );
+
+        // Only the DAI is left to withdraw
+        assert_eq!(97_000, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(vec!["bob.near".to_string()], contract.get_refund_failures());
+        assert!(contract
+            .get_deposit_accounts()
+            .contains(&"bob.near".to_string()));
+
+        let token_contributions = contract.get_token_contributions(bob());
+        assert_eq!(1, token_contributions.len());
+        assert_eq!(dai_account_id(), token_contributions[0].token_id);
+        assert_eq!(97 * DAI / 10, token_contributions[0].amount);
+
+        let (_, nep_141_balance) = &contract.get_token_balances()[0];
+        assert_eq!(0, nep_141_balance.amount);
+    }
+
+    #[test]
+    fn withdraw_debits_token_balances() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        let context = get_context(dai_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(10 * DAI), "".to_string());
+
+        let mut context = get_context(bob());
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+
+        // Debited while the transfers are in flight, the fees stay
+        for (_, token_balance) in contract.get_token_balances() {
+            assert_eq!(0, token_balance.amount);
+        }
+        assert_eq!(3_000, contract.get_token_balances()[0].1.fees);
+
+        // The DAI transfer fails and its balance is restored
+        set_promise_result(&context, PromiseResult::Failed);
+
+        contract.on_withdraw_callback(
+            bob(),
+            bob(),
+            dai_account_id(),
+            U128(97_000),
+            U128(97 * DAI / 10),
+            U128(0),
+        );
+
+        let token_balances = contract.get_token_balances();
+        assert_eq!(0, token_balances[0].1.amount);
+        assert_eq!(dai_account_id(), token_balances[1].0);
+        assert_eq!(97 * DAI / 10, token_balances[1].1.amount);
+    }
+
+    #[test]
+    fn ft_transfer_moves_token_contributions() {
+        let context = get_context(dai_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(10 * DAI), "".to_string());
+
+        register_account(&mut contract, alice());
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.ft_transfer(alice(), U128(48_500), None);
+
+        // Alice gets the DAI backing her receipts, bob keeps the fees he paid
+        let alice_tokens = contract.get_token_contributions(alice());
+        assert_eq!(dai_account_id(), alice_tokens[0].token_id);
+        assert_eq!(485 * DAI / 100, alice_tokens[0].amount);
+        assert_eq!(48_500, alice_tokens[0].receipts);
+        assert_eq!(0, alice_tokens[0].fees);
+
+        let bob_tokens = contract.get_token_contributions(bob());
+        assert_eq!(485 * DAI / 100, bob_tokens[0].amount);
+        assert_eq!(48_500, bob_tokens[0].receipts);
+        assert_eq!(3 * DAI / 10, bob_tokens[0].fees);
+
+        assert!(contract
+            .get_deposit_accounts()
+            .contains(&"alice.near".to_string()));
+    }
+
+    #[test]
+    fn claim_fees_each_token() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(500_000), "".to_string());
+
+        let context = get_context(dai_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(alice(), U128(50 * DAI), "".to_string());
+
+        let mut context = get_context(fees_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+
+        let recipient = &contract.get_fees().recipients[0];
+        assert!(recipient.claimed);
+        assert_eq!(vec![dai_account_id()], recipient.claimed_tokens);
+
+        // The DAI transfer fails, only DAI can be claimed again
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_claim_fees_callback(
+            fees_account_id(),
+            dai_account_id(),
+            U128(15 * DAI / 10)
+        ));
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"claim_fees_failed","data":[{"account_id":"fees.near","amount":"1500000000000000000","token_id":"dai.near"}]}"#,
+            ]
+        );
+
+        let recipient = &contract.get_fees().recipients[0];
+        assert!(recipient.claimed);
+        assert!(recipient.claimed_tokens.is_empty());
+    }
+
+    #[test]
+    fn delegate_funds_transfers_each_token() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_accepted_tokens(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(500_000), "".to_string());
+
+        let context = get_context(dai_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(alice(), U128(50 * DAI), "".to_string());
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(EscrowStatus::Delegating, contract.get_status());
+
+        let token_balances = contract.get_token_balances();
+        assert_eq!(485_000, token_balances[0].1.delegated);
+        assert_eq!(485 * DAI / 10, token_balances[1].1.delegated);
+
+        // The DAI transfer fails, it is sent again on the next delegate_funds
+        set_promise_result(&context, PromiseResult::Failed);
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            dai_account_id(),
+            U128(485 * DAI / 10),
+        );
+
+        assert_eq!(EscrowStatus::Succeeded, contract.get_status());
+
+        let token_balances = contract.get_token_balances();
+        assert_eq!(485_000, token_balances[0].1.delegated);
+        assert_eq!(0, token_balances[1].1.delegated);
+
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(485 * DAI / 10, contract.get_token_balances()[1].1.delegated);
+    }
+
+    const NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+
+    fn near_token_id() -> AccountId {
+        AccountId::new_unchecked(NEAR_TOKEN_ID.to_string())
+    }
+
+    // 1 NEAR is worth 3 nep_141
+    fn setup_contract_with_near(expires_at: u64, funding_amount_limit: u128) -> Escrow {
+        let mut metadata = new_metadata(expires_at, funding_amount_limit, None, None);
+        metadata.accepted_tokens = vec![AcceptedToken {
+            account_id: near_token_id(),
+            decimals: NEAR_DECIMALS,
+            rate_basis_points: 30_000,
+        }];
+
+        setup_contract_with(metadata, new_fees(300, None))
+    }
+
+    #[test]
+    fn deposit_near_success() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_near(expires_at, MIN_FUNDING_AMOUNT);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(10 * NEAR).build());
+
+        let refund = contract.deposit_near(None);
+
+        assert_eq!(0, refund.0);
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"deposit","data":[{"account_id":"bob.near","amount":"291000","fee":"9000","fee_rule":"flat","token_id":"near"}]}"#
+        );
+
+        assert_eq!(291_000, contract.ft.ft_balance_of(bob()).0);
+        assert_eq!(300_000, contract.get_total_funds());
+
+        let (token_id, token_balance) = &contract.get_token_balances()[0];
+        assert_eq!(&near_token_id(), token_id);
+        assert_eq!(97 * NEAR / 10, token_balance.amount);
+        assert_eq!(3 * NEAR / 10, token_balance.fees);
+    }
+
+    #[test]
+    fn deposit_near_partial_fill_refunds_near() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_near(expires_at, MIN_FUNDING_AMOUNT);
+
+        // 40 NEAR are worth 1_200_000, 33.3334 NEAR cover the 1_000_000 unpaid
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(40 * NEAR).build());
+
+        let refund = contract.deposit_near(None);
+
+        assert_eq!(66_666 * NEAR / 10_000, refund.0);
+        assert!(contract.is_funding_reached());
+        assert_eq!(
+            333_334 * NEAR / 10_000,
+            contract.get_token_balances()[0].1.amount + contract.get_token_balances()[0].1.fees
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_MSG")]
+    fn deposit_near_income_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_near(expires_at, MIN_FUNDING_AMOUNT);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(NEAR).build());
+
+        contract.deposit_near(Some(DepositInstruction {
+            income: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NEAR_NOT_ACCEPTED")]
+    fn deposit_near_not_accepted_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(NEAR).build());
+
+        contract.deposit_near(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_NEP141")]
+    fn deposit_near_token_id_through_ft_on_transfer_err() {
+        let context = get_context(near_token_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_near(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(10 * NEAR), "".to_string());
+    }
+
+    fn setup_contract_with_tranches(expires_at: u64, funding_amount_limit: u128) -> Escrow {
+        let mut metadata = new_metadata(expires_at, funding_amount_limit, None, None);
+        metadata.approver_account_id = Some(alice());
+        metadata.tranches = vec![
+            Tranche {
+                basis_points: 3_000,
+                milestone: "Roof and facade".to_string(),
+                approved: false,
+                released: false,
+            },
+            Tranche {
+                basis_points: 7_000,
+                milestone: "Interior renovation".to_string(),
+                approved: false,
+                released: false,
+            },
+        ];
+
+        setup_contract_with(metadata, new_fees(300, None))
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_TRANCHES")]
+    fn new_tranches_not_adding_up_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.approver_account_id = Some(alice());
+        metadata.tranches = vec![Tranche {
+            basis_points: 5_000,
+            milestone: "Roof and facade".to_string(),
+            approved: false,
+            released: false,
+        }];
+
+        setup_contract_with(metadata, new_fees(300, None));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_APPROVER")]
+    fn approve_tranche_not_approver_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_tranches(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.approve_tranche(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_MAINTAINER")]
+    fn delegate_funds_not_maintainer_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TRANCHE_NOT_APPROVED")]
+    fn delegate_funds_tranche_not_approved_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_tranches(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+    }
+
+    #[test]
+    fn delegate_funds_releases_approved_tranches() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_tranches(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        // First tranche, 30% of the 970_000 raised net of fees
+        let context = get_context(alice());
+        testing_env!(context.build());
+
+        contract.approve_tranche(0);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"tranche_approved","data":[{"index":0,"milestone":"Roof and facade"}]}"#,
+            ]
+        );
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(
+            get_logs()[0],
+            r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"tranche_released","data":[{"index":0,"basis_points":3000}]}"#
+        );
+        assert_eq!(291_000, contract.get_token_balances()[0].1.delegated);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(291_000),
+        );
+
+        // The second tranche remains
+        assert_eq!(EscrowStatus::Succeeded, contract.get_status());
+
+        let context = get_context(alice());
+        testing_env!(context.build());
+
+        contract.approve_tranche(1);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(970_000, contract.get_token_balances()[0].1.delegated);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(679_000),
+        );
+
+        assert_eq!(EscrowStatus::Delegated, contract.get_status());
+    }
+
+    #[test]
+    fn delegate_funds_retries_failed_tranche_first() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract_with_tranches(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let context = get_context(alice());
+        testing_env!(context.build());
+
+        contract.approve_tranche(0);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(291_000),
+        );
+
+        assert_eq!(0, contract.get_token_balances()[0].1.delegated);
+
+        // The second tranche is not approved, the first one is sent again
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(291_000, contract.get_token_balances()[0].1.delegated);
+        assert!(!contract.get_metadata().tranches[1].released);
+    }
+
+    const VOTING_PERIOD: u64 = 1_000;
+
+    // Bob holds 582_000 receipts and alice 388_000, out of 970_000
+    fn setup_funded_contract_with_voting(tranches: Vec<Tranche>) -> Escrow {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.tranches = tranches;
+        metadata.voting = Some(VotingPolicy {
+            quorum_basis_points: 5_000,
+            threshold_basis_points: 5_000,
+            voting_period: VOTING_PERIOD,
+        });
+
+        let mut contract = setup_contract_with(metadata, new_fees(300, None));
+
+        contract.ft_on_transfer(bob(), U128(600_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(400_000), "".to_string());
+
+        contract
+    }
+
+    fn vote_as(contract: &mut Escrow, account_id: AccountId, approve: bool) {
+        let context = get_context(account_id);
+        testing_env!(context.build());
+
+        contract.vote(approve);
+    }
+
+    #[test]
+    fn delegate_funds_approved_by_vote() {
+        let mut contract = setup_funded_contract_with_voting(vec![]);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        assert_eq!(0, contract.propose_delegation());
+
+        vote_as(&mut contract, bob(), true);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"vote","data":[{"proposal_id":0,"account_id":"bob.near","approve":true,"weight":"582000"}]}"#,
+            ]
+        );
+
+        vote_as(&mut contract, alice(), false);
+
+        let proposal = contract.get_proposal().unwrap();
+        assert_eq!(582_000, proposal.approve_weight);
+        assert_eq!(388_000, proposal.reject_weight);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context
+            .block_timestamp(VOTING_PERIOD + 1)
+            .attached_deposit(1)
+            .build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(970_000, contract.get_token_balances()[0].1.delegated);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DELEGATION_NOT_APPROVED")]
+    fn delegate_funds_before_vote_ends_err() {
+        let mut contract = setup_funded_contract_with_voting(vec![]);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_delegation();
+
+        vote_as(&mut contract, bob(), true);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DELEGATION_NOT_APPROVED")]
+    fn delegate_funds_vote_quorum_not_reached_err() {
+        let mut contract = setup_funded_contract_with_voting(vec![]);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_delegation();
+
+        // 388_000 votes cast, the quorum is 485_000
+        vote_as(&mut contract, alice(), true);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context
+            .block_timestamp(VOTING_PERIOD + 1)
+            .attached_deposit(1)
+            .build());
+
+        contract.delegate_funds(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ALREADY_VOTED")]
+    fn vote_twice_err() {
+        let mut contract = setup_funded_contract_with_voting(vec![]);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_delegation();
+
+        vote_as(&mut contract, bob(), true);
+        vote_as(&mut contract, bob(), true);
+    }
+
+    #[test]
+    fn vote_weight_drops_when_receipts_move() {
+        let mut contract = setup_funded_contract_with_voting(vec![]);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_delegation();
+
+        vote_as(&mut contract, bob(), true);
+
+        // Bob moves receipts to alice during the vote, they only count once
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.ft_transfer(alice(), U128(300_000), None);
+
+        vote_as(&mut contract, alice(), false);
+
+        assert_eq!(282_000, contract.get_vote(bob()).unwrap().weight);
+        assert_eq!(688_000, contract.get_vote(alice()).unwrap().weight);
+
+        let proposal = contract.get_proposal().unwrap();
+        assert_eq!(282_000, proposal.approve_weight);
+        assert_eq!(688_000, proposal.reject_weight);
+        assert_eq!(
+            contract.ft_total_supply().0,
+            proposal.approve_weight + proposal.reject_weight
+        );
+    }
+
+    #[test]
+    fn delegate_funds_tranche_approved_by_vote() {
+        let mut contract = setup_funded_contract_with_voting(vec![
+            Tranche {
+                basis_points: 3_000,
+                milestone: "Roof and facade".to_string(),
+                approved: false,
+                released: false,
+            },
+            Tranche {
+                basis_points: 7_000,
+                milestone: "Interior renovation".to_string(),
+                approved: false,
+                released: false,
+            },
+        ]);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_delegation();
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"proposal","data":[{"id":0,"tranche":0,"ends_at":1000}]}"#,
+            ]
+        );
+
+        vote_as(&mut contract, bob(), true);
+        vote_as(&mut contract, alice(), true);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context
+            .block_timestamp(VOTING_PERIOD + 1)
+            .attached_deposit(1)
+            .build());
+
+        contract.delegate_funds(None);
+
+        assert_eq!(291_000, contract.get_token_balances()[0].1.delegated);
+        assert!(contract.get_metadata().tranches[0].approved);
+        assert!(!contract.get_metadata().tranches[1].approved);
+    }
+
+    fn take_snapshot(contract: &mut Escrow, account_id: AccountId, block_timestamp: u64) -> u64 {
+        let mut context = get_context(account_id);
+        testing_env!(context.block_timestamp(block_timestamp).build());
+
+        contract.snapshot()
+    }
+
+    #[test]
+    fn balance_of_at_snapshot() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(200_000), "".to_string());
+
+        assert_eq!(1, take_snapshot(&mut contract, maintainer_account_id(), 0));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"snapshot","data":[{"id":1,"taken_at":0}]}"#,
+            ]
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.ft_transfer(alice(), U128(50_000), None);
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        assert_eq!(144_000, contract.ft_balance_of(bob()).0);
+        assert_eq!(244_000, contract.ft_balance_of(alice()).0);
+        assert_eq!(97_000, contract.balance_of_at(bob(), 1).0);
+        assert_eq!(194_000, contract.balance_of_at(alice(), 1).0);
+        assert_eq!(291_000, contract.total_supply_at(1).0);
+
+        assert_eq!(2, take_snapshot(&mut contract, maintainer_account_id(), 0));
+
+        assert_eq!(144_000, contract.balance_of_at(bob(), 2).0);
+        assert_eq!(388_000, contract.total_supply_at(2).0);
+        assert_eq!(97_000, contract.balance_of_at(bob(), 1).0);
+    }
+
+    #[test]
+    fn balance_of_at_many_snapshots() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        // Bob deposits after every other snapshot, one checkpoint each
+        for snapshot_id in 1..=10 {
+            take_snapshot(&mut contract, maintainer_account_id(), 0);
+
+            if snapshot_id % 2 == 0 {
+                let context = get_context(nep_141_account_id());
+                testing_env!(context.build());
+
+                contract.ft_on_transfer(bob(), U128(10_000), "".to_string());
+            }
+        }
+
+        assert_eq!(5, contract.get_balance_checkpoints(&bob()).len());
+        assert_eq!(48_500, contract.ft_balance_of(bob()).0);
+
+        for snapshot_id in 1..=10 {
+            assert_eq!(
+                9_700 * ((snapshot_id as u128 - 1) / 2),
+                contract.balance_of_at(bob(), snapshot_id).0
+            );
+            assert_eq!(
+                contract.balance_of_at(bob(), snapshot_id).0,
+                contract.total_supply_at(snapshot_id).0
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SNAPSHOT_NOT_FOUND")]
+    fn balance_of_at_snapshot_not_found_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        take_snapshot(&mut contract, maintainer_account_id(), 0);
+
+        contract.balance_of_at(bob(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_MAINTAINER")]
+    fn snapshot_not_maintainer_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        take_snapshot(&mut contract, bob(), 0);
+    }
+
+    #[test]
+    fn snapshot_scheduled() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.snapshot_interval = Some(1_000);
+
+        let mut contract = setup_contract_with(metadata, new_fees(300, None));
+
+        assert_eq!(1, take_snapshot(&mut contract, bob(), 500));
+        assert_eq!(2, take_snapshot(&mut contract, alice(), 1_500));
+        assert_eq!(1_500, contract.get_snapshot(2).unwrap().taken_at);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SNAPSHOT_TOO_EARLY")]
+    fn snapshot_scheduled_too_early_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.snapshot_interval = Some(1_000);
+
+        let mut contract = setup_contract_with(metadata, new_fees(300, None));
+
+        take_snapshot(&mut contract, bob(), 500);
+        take_snapshot(&mut contract, alice(), 1_000);
+    }
+
+    const INCOME_MSG: &str = r#"{"income": true}"#;
+
+    // Bob holds 582_000 receipts and alice 388_000, out of 970_000
+    fn setup_funded_contract() -> Escrow {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(600_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(400_000), "".to_string());
+
+        contract
+    }
+
+    fn deposit_income(contract: &mut Escrow, amount: u128) {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(
+            maintainer_account_id(),
+            U128(amount),
+            INCOME_MSG.to_string(),
+        );
+    }
+
+    #[test]
+    fn income_split_pro_rata_across_transfers() {
+        let mut contract = setup_funded_contract();
+
+        deposit_income(&mut contract, 97_000);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"income","data":[{"amount":"97000","amount_per_share":"100000000000000000"}]}"#,
+            ]
+        );
+        assert_eq!(58_200, contract.get_claimable_income(bob()).0);
+        assert_eq!(38_800, contract.get_claimable_income(alice()).0);
+
+        // The income earned so far stays with bob
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.ft_transfer(alice(), U128(282_000), None);
+
+        assert_eq!(58_200, contract.get_claimable_income(bob()).0);
+        assert_eq!(38_800, contract.get_claimable_income(alice()).0);
+
+        deposit_income(&mut contract, 97_000);
+
+        assert_eq!(88_200, contract.get_claimable_income(bob()).0);
+        assert_eq!(105_800, contract.get_claimable_income(alice()).0);
+        assert_eq!(194_000, contract.get_income().amount);
+    }
+
+    #[test]
+    fn claim_income_success() {
+        let mut contract = setup_funded_contract();
+
+        deposit_income(&mut contract, 97_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_income();
+
+        assert_eq!(0, contract.get_claimable_income(bob()).0);
+        assert_eq!(58_200, contract.get_income().claimed);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_claim_income_callback(bob(), U128(58_200)));
+        assert_eq!(58_200, contract.get_claimable_income(bob()).0);
+        assert_eq!(0, contract.get_income().claimed);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.claim_income();
+
+        assert!(contract.on_claim_income_callback(bob(), U128(58_200)));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"claim_income","data":[{"account_id":"bob.near","amount":"58200"}]}"#,
+            ]
+        );
+    }
+
+    // Bob holds 97% of funding, four deposits of rent are paid out to him
+    fn assert_income_at_scale(funding: Balance, rent: Balance) {
+        let mut contract = setup_contract(add_expires_at_nanos(100), funding);
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(funding), "".to_string());
+
+        for _ in 0..4 {
+            deposit_income(&mut contract, rent);
+        }
+
+        // Each deposit rounds the amount per share down, by less than one unit per PER_SHARE_PRECISION receipts
+        let claimable = contract.get_claimable_income(bob()).0;
+        assert!(4 * rent - claimable <= 4 * (funding / PER_SHARE_PRECISION + 1));
+
+        // Settled on the transfer, moving receipts does not move the income earned
+        register_account(&mut contract, alice());
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.ft_transfer(alice(), U128(funding / 2), None);
+
+        assert_eq!(claimable, contract.get_claimable_income(bob()).0);
+        assert_eq!(0, contract.get_claimable_income(alice()).0);
+
+        contract.claim_income();
+
+        assert_eq!(claimable, contract.get_income().claimed);
+    }
+
+    #[test]
+    fn income_with_18_decimals() {
+        let unit = 10u128.pow(18);
+
+        assert_income_at_scale(1_000 * unit, 100 * unit);
+    }
+
+    #[test]
+    fn income_with_24_decimals() {
+        let unit = 10u128.pow(24);
+
+        assert_income_at_scale(1_000_000 * unit, 100_000 * unit);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_INCOME")]
+    fn claim_income_nothing_to_claim_err() {
+        let mut contract = setup_funded_contract();
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_income();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_MAINTAINER")]
+    fn income_not_maintainer_err() {
+        let mut contract = setup_funded_contract();
+
+        contract.ft_on_transfer(bob(), U128(97_000), INCOME_MSG.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INCOME_NOT_ALLOWED")]
+    fn income_before_funding_succeeded_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        deposit_income(&mut contract, 97_000);
+    }
+
+    const BUYBACK_MSG: &str = r#"{"buyback": {"price_basis_points": 5000, "ends_at": 1000}}"#;
+
+    // Delegating, bob holds 582_000 receipts and alice 388_000
+    fn setup_delegated_contract() -> Escrow {
+        let mut contract = setup_funded_contract();
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        contract
+    }
+
+    fn deposit_buyback(contract: &mut Escrow, amount: u128) {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(
+            maintainer_account_id(),
+            U128(amount),
+            BUYBACK_MSG.to_string(),
+        );
+    }
+
+    #[test]
+    fn redeem_success() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"buyback","data":[{"price_basis_points":5000,"ends_at":1000,"liquidity":"100000"}]}"#,
+            ]
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+
+        assert_eq!(482_000, contract.ft_balance_of(bob()).0);
+        assert_eq!(870_000, contract.ft_total_supply().0);
+
+        let buyback = contract.get_buyback().unwrap();
+        assert_eq!(50_000, buyback.paid);
+        assert_eq!(100_000, buyback.redeemed);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert!(contract.on_redeem_callback(bob(), U128(100_000), U128(50_000), vec![]));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"bob.near","amount":"100000"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"redeem","data":[{"account_id":"bob.near","receipts":"100000","amount":"50000"}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn redeem_transfer_failed_mints_receipts_back() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_redeem_callback(
+            bob(),
+            U128(100_000),
+            U128(50_000),
+            vec![TokenContribution {
+                token_id: nep_141_account_id(),
+                amount: 100_000,
+                fees: 0,
+                receipts: 100_000,
+            }]
+        ));
+        assert_eq!(582_000, contract.ft_balance_of(bob()).0);
+        assert_eq!(970_000, contract.ft_total_supply().0);
+        assert_eq!(0, contract.get_buyback().unwrap().paid);
+
+        let token_contributions = contract.get_token_contributions(bob());
+        assert_eq!(582_000, token_contributions[0].receipts);
+        assert_eq!(582_000, token_contributions[0].amount);
+        assert!(contract.get_accounting_report().balanced);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_LIQUIDITY")]
+    fn redeem_not_enough_liquidity_err() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(300_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_ACTIVE_BUYBACK")]
+    fn redeem_after_buyback_ended_err() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.block_timestamp(1_001).attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BUYBACK_NOT_ALLOWED")]
+    fn buyback_before_delegation_err() {
+        let mut contract = setup_funded_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BUYBACK_NOT_ENDED")]
+    fn close_buyback_before_end_err() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        contract.close_buyback();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REDEEMS_PENDING")]
+    fn close_buyback_with_pending_redeem_err() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+
+        // The redeem transfer may still fail and give its payout back
+        let mut context = get_context(alice());
+        testing_env!(context.block_timestamp(1_001).build());
+
+        contract.close_buyback();
+    }
+
+    #[test]
+    fn close_buyback_returns_unused_liquidity() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert!(contract.on_redeem_callback(bob(), U128(100_000), U128(50_000), vec![]));
+
+        let mut context = get_context(alice());
+        testing_env!(context.block_timestamp(1_001).build());
+
+        contract.close_buyback();
+
+        assert!(contract.get_buyback().unwrap().closed);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_close_buyback_callback(U128(50_000)));
+        assert!(!contract.get_buyback().unwrap().closed);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.close_buyback();
+
+        assert!(contract.on_close_buyback_callback(U128(50_000)));
+        assert!(contract.get_buyback().is_none());
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"buyback_closed","data":[{"amount":"50000"}]}"#,
+            ]
+        );
+    }
+
+    const SALE_MSG: &str = r#"{"sale": true}"#;
+
+    // Settling with 980_000 proceeds after a 2% sale fee
+    // Bob holds 582_000 receipts and alice 388_000
+    fn setup_sold_contract() -> Escrow {
+        setup_sold_contract_at(1)
+    }
+
+    // setup_sold_contract with every amount in units of unit
+    fn setup_sold_contract_at(unit: Balance) -> Escrow {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut fees = new_fees(300, None);
+        fees.sale_basis_points = 200;
+        let mut contract = setup_contract_with(
+            new_metadata(
+                add_expires_at_nanos(100),
+                MIN_FUNDING_AMOUNT * unit,
+                None,
+                None,
+            ),
+            fees,
+        );
+
+        contract.ft_on_transfer(bob(), U128(600_000 * unit), "".to_string());
+        contract.ft_on_transfer(alice(), U128(400_000 * unit), "".to_string());
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(970_000 * unit),
+        );
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(
+            maintainer_account_id(),
+            U128(1_000_000 * unit),
+            SALE_MSG.to_string(),
+        );
+
+        contract
+    }
+
+    fn settle_as(contract: &mut Escrow, account_id: AccountId, promise_result: PromiseResult) {
+        let mut context = get_context(account_id);
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.settle();
+
+        set_promise_result(&context, promise_result);
+    }
+
+    #[test]
+    fn settle_pays_proceeds_and_closes() {
+        let mut contract = setup_sold_contract();
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"sale","data":[{"proceeds":"980000","fee":"20000"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"settling"}]}"#,
+            ]
+        );
+
+        settle_as(&mut contract, bob(), PromiseResult::Successful(vec![]));
+
+        // 582_000 * 980_000 / 970_000 = 588_000, less the per receipt rounding
+        assert_eq!(587_999, contract.get_settlement().unwrap().paid);
+        assert!(contract.on_settle_callback(bob(), U128(582_000), U128(587_999), vec![]));
+        assert_eq!(EscrowStatus::Settling, contract.get_status());
+
+        settle_as(&mut contract, alice(), PromiseResult::Successful(vec![]));
+
+        // The last receipts get the rounding remainder
+        assert!(contract.on_settle_callback(alice(), U128(388_000), U128(392_001), vec![]));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"alice.near","amount":"388000"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"settle","data":[{"account_id":"alice.near","receipts":"388000","amount":"392001"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"status","data":[{"status":"closed"}]}"#,
+            ]
+        );
+        assert_eq!(EscrowStatus::Closed, contract.get_status());
+        assert_eq!(0, contract.ft_total_supply().0);
+        assert_eq!(980_000, contract.get_settlement().unwrap().paid);
+    }
+
+    #[test]
+    fn settle_with_18_decimals() {
+        let unit = 10u128.pow(18);
+        let mut contract = setup_sold_contract_at(unit);
+
+        settle_as(&mut contract, bob(), PromiseResult::Successful(vec![]));
+
+        // Less than one unit per PER_SHARE_PRECISION receipts is lost to the rounding
+        let paid = contract.get_settlement().unwrap().paid;
+        assert!(588_000 * unit - paid <= 582_000 * unit / PER_SHARE_PRECISION + 1);
+
+        settle_as(&mut contract, alice(), PromiseResult::Successful(vec![]));
+
+        // The last receipts get the rounding remainder
+        assert_eq!(980_000 * unit, contract.get_settlement().unwrap().paid);
+        assert_eq!(0, contract.ft_total_supply().0);
+    }
+
+    #[test]
+    fn settle_transfer_failed_mints_receipts_back() {
+        let mut contract = setup_sold_contract();
+
+        settle_as(&mut contract, bob(), PromiseResult::Failed);
+
+        assert!(contract.get_accounting_report().balanced);
+        assert!(!contract.on_settle_callback(
+            bob(),
+            U128(582_000),
+            U128(587_999),
+            vec![TokenContribution {
+                token_id: nep_141_account_id(),
+                amount: 582_000,
+                fees: 0,
+                receipts: 582_000,
+            }]
+        ));
+        assert_eq!(582_000, contract.ft_balance_of(bob()).0);
+        assert!(contract.get_accounting_report().balanced);
+
+        let settlement = contract.get_settlement().unwrap();
+        assert_eq!(0, settlement.paid);
+        assert_eq!(0, settlement.burned);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SETTLE_NOT_ALLOWED")]
+    fn settle_before_sale_err() {
+        let mut contract = setup_delegated_contract();
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.settle();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SALE_NOT_ALLOWED")]
+    fn sale_before_delegated_err() {
+        let mut contract = setup_delegated_contract();
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(
+            maintainer_account_id(),
+            U128(1_000_000),
+            SALE_MSG.to_string(),
+        );
+    }
+
+    #[test]
+    fn claim_fees_sale_fee() {
+        let mut contract = setup_sold_contract();
+
+        let mut context = get_context(fees_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+
+        assert!(contract.get_fees().recipients[0].sale_claimed);
+
+        set_promise_result(&context, PromiseResult::Failed);
+
+        assert!(!contract.on_claim_sale_fees_callback(fees_account_id(), U128(20_000)));
+        assert!(!contract.get_fees().recipients[0].sale_claimed);
+        assert!(contract.get_fees().recipients[0].claimed);
+
+        // Only the sale fee is left to claim
+        testing_env!(context.build());
+
+        contract.claim_fees();
+
+        assert!(contract.get_fees().recipients[0].sale_claimed);
+    }
+
+    #[test]
+    fn claim_referral_fees_after_sale() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut fees = new_fees(300, None);
+        fees.referral_basis_points = 5_000;
+        let mut contract = setup_contract_with(
+            new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        let msg = r#"{"referrer_id":"alice.near"}"#.to_string();
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), msg);
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        contract.on_delegate_funds_callback(
+            maintainer_account_id(),
+            nep_141_account_id(),
+            U128(970_000),
+        );
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(
+            maintainer_account_id(),
+            U128(1_000_000),
+            SALE_MSG.to_string(),
+        );
+
+        assert_eq!(EscrowStatus::Settling, contract.get_status());
+
+        // Alice did not claim her half of the fee before the sale
+        let mut context = get_context(alice());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_referral_fees();
+
+        assert_eq!(
+            15_000,
+            contract.get_referral_stats(alice()).unwrap().fees_claimed
+        );
+    }
+
+    #[test]
+    fn accounting_report_reconciles_supply_and_fees() {
+        let mut contract = setup_funded_contract();
+
+        assert_eq!(
+            contract.ft_total_supply().0,
+            contract.ft_balance_of(bob()).0 + contract.ft_balance_of(alice()).0
+        );
+
+        deposit_income(&mut contract, 97_000);
+
+        let report = contract.get_accounting_report();
+        assert!(report.balanced);
+        assert_eq!(970_000, report.total_supply.0);
+        assert_eq!(970_000, report.backed_supply.0);
+        assert_eq!(1_000_000, report.total_funds.0);
+        assert_eq!(30_000, report.fees_amount.0);
+
+        let token = &report.tokens[0];
+        assert_eq!(nep_141_account_id(), token.token_id);
+        assert_eq!(970_000, token.funds.0);
+        assert_eq!(30_000, token.fees_owed.0);
+        assert_eq!(97_000, token.obligations.0);
+        assert_eq!(1_097_000, token.held.0);
+    }
+
+    #[test]
+    fn accounting_report_after_redeem() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert!(contract.on_redeem_callback(bob(), U128(100_000), U128(50_000), vec![]));
+
+        let report = contract.get_accounting_report();
+        assert!(report.balanced);
+        assert_eq!(870_000, report.total_supply.0);
+        assert_eq!(870_000, report.backed_supply.0);
+
+        let token = &report.tokens[0];
+        assert_eq!(0, token.funds.0);
+        assert_eq!(50_000, token.obligations.0);
+    }
+
+    #[test]
+    fn accounting_report_after_settle() {
+        let mut contract = setup_sold_contract();
+
+        settle_as(&mut contract, bob(), PromiseResult::Successful(vec![]));
+
+        assert!(contract.on_settle_callback(bob(), U128(582_000), U128(587_999), vec![]));
+
+        let report = contract.get_accounting_report();
+        assert!(report.balanced);
+        assert_eq!(388_000, report.total_supply.0);
+        assert_eq!(388_000, report.backed_supply.0);
+
+        let token = &report.tokens[0];
+        assert_eq!(0, token.funds.0);
+        assert_eq!(392_001, token.obligations.0);
+    }
+
+    #[test]
+    fn deposit_mints_fees_to_recipients() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut fees = new_fees(300, None);
+        fees.mint_policy = FeeMintPolicy::MintToRecipients;
+        let mut contract = setup_contract_with(
+            new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        assert_eq!(
+            get_logs()[2],
+            r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{"owner_id":"fees.near","amount":"3000","memo":"fee"}]}"#
+        );
+        assert_eq!(97_000, contract.ft_balance_of(bob()).0);
+        assert_eq!(3_000, contract.ft_balance_of(fees_account_id()).0);
+        assert_eq!(100_000, contract.ft_total_supply().0);
+
+        let report = contract.get_accounting_report();
+        assert!(report.balanced);
+        assert_eq!(report.total_funds, report.total_supply);
+        assert_eq!(100_000, report.tokens[0].funds.0);
+        assert_eq!(0, report.tokens[0].fees_owed.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEES_MINTED")]
+    fn claim_fees_minted_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut fees = new_fees(300, None);
+        fees.mint_policy = FeeMintPolicy::MintToRecipients;
+        let mut contract = setup_contract_with(
+            new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), "".to_string());
+
+        let mut context = get_context(fees_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_FEE_MINT_POLICY")]
+    fn new_mint_fees_with_referrals_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut fees = new_fees(300, None);
+        fees.mint_policy = FeeMintPolicy::MintToRecipients;
+        fees.referral_basis_points = 1_000;
+
+        setup_contract_with(
+            new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+    }
+
+    // Holds 1_000_000 nep_141 according to the ledger, 970_000 of funds and 30_000 of fees
+    fn setup_contract_pausing_on_discrepancy() -> Escrow {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.pause_on_discrepancy = true;
+
+        let mut contract = setup_contract_with(metadata, new_fees(300, None));
+
+        contract.ft_on_transfer(bob(), U128(600_000), "".to_string());
+        contract.ft_on_transfer(alice(), U128(400_000), "".to_string());
+
+        contract
+    }
+
+    fn reconcile_with_balance(contract: &mut Escrow, balance: &str) -> bool {
+        let context = get_context(accounts(0));
+
+        set_promise_result(
+            &context,
+            PromiseResult::Successful(format!("\"{}\"", balance).into_bytes()),
+        );
+
+        contract.on_reconcile_callback(nep_141_account_id())
+    }
+
+    #[test]
+    fn reconcile_balance_matches_ledger() {
+        let mut contract = setup_contract_pausing_on_discrepancy();
+
+        assert!(reconcile_with_balance(&mut contract, "1000000"));
+        assert!(get_logs().is_empty());
+        assert!(!contract.get_pause().deposits);
+    }
+
+    #[test]
+    fn reconcile_deficit_pauses() {
+        let mut contract = setup_contract_pausing_on_discrepancy();
+
+        assert!(!reconcile_with_balance(&mut contract, "990000"));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"discrepancy","data":[{"token_id":"nep141.near","expected":"1000000","actual":"990000"}]}"#,
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"pause","data":[{"operations":["deposits","withdrawals","delegation","fee_claims"],"paused":true}]}"#,
+            ]
+        );
+
+        let pause = contract.get_pause();
+        assert!(pause.deposits && pause.withdrawals && pause.delegation && pause.fee_claims);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.unpause(ALL_OPERATIONS.to_vec());
+
+        let pause = contract.get_pause();
+        assert!(!pause.deposits && !pause.withdrawals && !pause.delegation && !pause.fee_claims);
+    }
+
+    #[test]
+    fn reconcile_while_closing_buyback() {
+        let mut contract = setup_delegated_contract();
+        contract.metadata.pause_on_discrepancy = true;
+
+        deposit_buyback(&mut contract, 100_000);
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+
+        set_promise_result(&context, PromiseResult::Successful(vec![]));
+
+        assert!(contract.on_redeem_callback(bob(), U128(100_000), U128(50_000), vec![]));
+
+        let held = contract.get_accounting_report().tokens[0].held.0;
+
+        let mut context = get_context(alice());
+        testing_env!(context.block_timestamp(1_001).build());
+
+        contract.close_buyback();
+
+        // The 50_000 of unused liquidity already left the escrow, the callback is not back yet
+        assert_eq!(
+            held - 50_000,
+            contract.get_accounting_report().tokens[0].held.0
+        );
+        assert!(reconcile_with_balance(
+            &mut contract,
+            &(held - 50_000).to_string()
+        ));
+        assert!(!contract.get_pause().withdrawals);
+    }
+
+    #[test]
+    fn reconcile_surplus_does_not_pause() {
+        let mut contract = setup_contract_pausing_on_discrepancy();
+
+        assert!(!reconcile_with_balance(&mut contract, "1000500"));
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"discrepancy","data":[{"token_id":"nep141.near","expected":"1000000","actual":"1000500"}]}"#,
+            ]
+        );
+        assert!(!contract.get_pause().deposits);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEE_CLAIMS_PAUSED")]
+    fn claim_fees_paused_by_discrepancy_err() {
+        let mut contract = setup_contract_pausing_on_discrepancy();
+
+        reconcile_with_balance(&mut contract, "990000");
+
+        let mut context = get_context(fees_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+    }
+
+    //###############
+    // Test Guardian
+    //###############
+
+    fn pause_as(contract: &mut Escrow, account_id: AccountId, operations: Vec<Operation>) {
+        let context = get_context(account_id);
+        testing_env!(context.build());
+
+        contract.pause(operations);
+    }
+
+    #[test]
+    fn pause_operations_independently() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Deposits, Operation::FeeClaims],
+        );
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"pause","data":[{"operations":["deposits","fee_claims"],"paused":true}]}"#,
+            ]
+        );
+
+        let pause = contract.get_pause();
+        assert!(pause.deposits && pause.fee_claims);
+        assert!(!pause.withdrawals && !pause.delegation);
+
+        // Deposits resume, fee claims stay paused, fee_claims is already paused
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.unpause(vec![Operation::Deposits, Operation::Withdrawals]);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"pause","data":[{"operations":["deposits"],"paused":false}]}"#,
+            ]
+        );
+
+        let pause = contract.get_pause();
+        assert!(!pause.deposits && pause.fee_claims);
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        assert_eq!(97_000, contract.ft_balance_of(bob()).0);
+    }
+
+    #[test]
+    fn withdraw_while_deposits_paused() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Deposits],
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+
+        assert_eq!(0, contract.ft_balance_of(bob()).0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSITS_PAUSED")]
+    fn deposit_paused_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Deposits],
+        );
+
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSITS_PAUSED")]
+    fn deposit_near_paused_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut contract = setup_contract_with_near(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Deposits],
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(NEAR).build());
+
+        contract.deposit_near(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSITS_PAUSED")]
+    fn income_paused_err() {
+        let mut contract = setup_funded_contract();
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Deposits],
+        );
+
+        deposit_income(&mut contract, 97_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn withdraw_paused_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context
+            .block_timestamp(expires_at + 1000)
+            .attached_deposit(1)
+            .build());
+
+        contract.withdraw(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn refund_batch_paused_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let expires_at = add_expires_at_nanos(100);
+        let mut contract = setup_contract(expires_at, MIN_FUNDING_AMOUNT);
+
+        contract.ft_on_transfer(bob(), U128(100_000), "".to_string());
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+
+        let mut context = get_context(accounts(3));
+        testing_env!(context.block_timestamp(expires_at + 1000).build());
+
+        contract.refund_batch(0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn claim_income_paused_err() {
+        let mut contract = setup_funded_contract();
+
+        deposit_income(&mut contract, 97_000);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_income();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn redeem_paused_err() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+
+        let mut context = get_context(bob());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.redeem(U128(100_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn close_buyback_paused_err() {
+        let mut contract = setup_delegated_contract();
+
+        deposit_buyback(&mut contract, 100_000);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.block_timestamp(2_000).attached_deposit(1).build());
+
+        contract.close_buyback();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn settle_paused_err() {
+        let mut contract = setup_sold_contract();
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+
+        settle_as(&mut contract, bob(), PromiseResult::Successful(vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DELEGATION_PAUSED")]
+    fn delegate_funds_paused_err() {
+        let mut contract = setup_funded_contract();
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Delegation],
+        );
+
+        let mut context = get_context(maintainer_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.delegate_funds(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEE_CLAIMS_PAUSED")]
+    fn claim_fees_paused_err() {
+        let mut contract = setup_funded_contract();
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::FeeClaims],
+        );
+
+        let mut context = get_context(fees_account_id());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_fees();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FEE_CLAIMS_PAUSED")]
+    fn claim_referral_fees_paused_err() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut fees = new_fees(300, None);
+        fees.referral_basis_points = 5_000;
+        let mut contract = setup_contract_with(
+            new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None),
+            fees,
+        );
+
+        let msg = r#"{"referrer_id":"alice.near"}"#.to_string();
+        contract.ft_on_transfer(bob(), U128(MIN_FUNDING_AMOUNT), msg);
+
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::FeeClaims],
+        );
+
+        let mut context = get_context(alice());
+        testing_env!(context.attached_deposit(1).build());
+
+        contract.claim_referral_fees();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_GUARDIAN")]
+    fn pause_not_guardian_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        pause_as(&mut contract, bob(), vec![Operation::Deposits]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_GUARDIAN")]
+    fn unpause_not_guardian_err() {
+        let mut contract = setup_contract_pausing_on_discrepancy();
+
+        reconcile_with_balance(&mut contract, "990000");
+
+        let context = get_context(bob());
+        testing_env!(context.build());
+
+        contract.unpause(ALL_OPERATIONS.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_GUARDIAN")]
+    fn guardian_set_at_new_replaces_maintainer() {
+        let context = get_context(nep_141_account_id());
+        testing_env!(context.build());
+
+        let mut metadata = new_metadata(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT, None, None);
+        metadata.guardian_account_id = Some(alice());
+
+        let mut contract = setup_contract_with(metadata, new_fees(300, None));
+
+        assert_eq!(alice(), contract.get_guardian_account_id());
+
+        pause_as(&mut contract, alice(), vec![Operation::Deposits]);
+        pause_as(
+            &mut contract,
+            maintainer_account_id(),
+            vec![Operation::Withdrawals],
+        );
+    }
+
+    #[test]
+    fn guardian_rotation() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_guardian(alice());
+
+        assert_eq!(maintainer_account_id(), contract.get_guardian_account_id());
+        assert_eq!(Some(alice()), contract.get_pending_guardian_account_id());
+
+        let context = get_context(alice());
+        testing_env!(context.build());
+
+        contract.accept_guardian();
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"splitfund","version":"1.0.0","event":"guardian","data":[{"guardian_account_id":"alice.near","pending":false}]}"#,
+            ]
+        );
+        assert_eq!(alice(), contract.get_guardian_account_id());
+        assert_eq!(None, contract.get_pending_guardian_account_id());
+
+        pause_as(&mut contract, alice(), vec![Operation::Delegation]);
+
+        assert!(contract.get_pause().delegation);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_PENDING_GUARDIAN")]
+    fn accept_guardian_not_pending_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(maintainer_account_id());
+        testing_env!(context.build());
+
+        contract.propose_guardian(alice());
+
+        let context = get_context(bob());
+        testing_env!(context.build());
+
+        contract.accept_guardian();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ONLY_GUARDIAN")]
+    fn propose_guardian_not_guardian_err() {
+        let mut contract = setup_contract(add_expires_at_nanos(100), MIN_FUNDING_AMOUNT);
+
+        let context = get_context(bob());
+        testing_env!(context.build());
+
+        contract.propose_guardian(bob());
     }
 }
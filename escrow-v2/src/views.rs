@@ -1,4 +1,4 @@
-use near_sdk::{env, near_bindgen, Balance};
+use near_sdk::{env, json_types::U128, near_bindgen, AccountId, Balance};
 
 use crate::storage::*;
 
@@ -8,12 +8,30 @@ impl Escrow {
         self.get_metadata().funding_amount_limit - self.get_metadata().unpaid_amount
     }
 
+    /**
+     * Stored status, advanced by the expiration date and the funds raised
+     * Mutating methods store it before checking their allowed transitions
+     */
+    pub fn get_status(&self) -> EscrowStatus {
+        match self.status {
+            EscrowStatus::Funding if self.is_funding_reached() => EscrowStatus::Succeeded,
+            EscrowStatus::Funding if self.has_contract_expired() && self.is_soft_cap_reached() => {
+                EscrowStatus::Succeeded
+            }
+            EscrowStatus::Funding if self.has_contract_expired() => EscrowStatus::Failed,
+            status => status,
+        }
+    }
+
     pub fn is_deposit_allowed(&self) -> bool {
-        !self.has_contract_expired() && !self.is_funding_reached()
+        self.get_status() == EscrowStatus::Funding
     }
 
     pub fn is_withdrawal_allowed(&self) -> bool {
-        self.has_contract_expired() && !self.is_funding_reached()
+        matches!(
+            self.get_status(),
+            EscrowStatus::Failed | EscrowStatus::Cancelled
+        )
     }
 
     pub fn has_contract_expired(&self) -> bool {
@@ -24,6 +42,13 @@ impl Escrow {
         self.get_total_funds() >= self.get_metadata().funding_amount_limit
     }
 
+    pub fn is_soft_cap_reached(&self) -> bool {
+        match self.get_metadata().soft_cap {
+            Some(soft_cap) => self.get_total_funds() >= soft_cap,
+            None => false,
+        }
+    }
+
     pub fn get_deposit_accounts(&self) -> Vec<String> {
         let mut accounts = vec![];
 
@@ -34,10 +59,152 @@ impl Escrow {
         accounts
     }
 
+    pub fn get_refund_failures(&self) -> Vec<String> {
+        let mut accounts = vec![];
+
+        for i in self.refund_failures.to_vec() {
+            accounts.push(i.to_string());
+        }
+
+        accounts
+    }
+
     pub fn get_fees(&self) -> Fees {
         self.fees.clone()
     }
 
+    pub fn get_contribution(&self, account_id: AccountId) -> Balance {
+        self.contributions
+            .get(&account_id)
+            .map_or(0, |contribution| contribution.amount)
+    }
+
+    /**
+     * Funds held in each NEP141, in its own units
+     */
+    pub fn get_token_balances(&self) -> Vec<(AccountId, TokenBalance)> {
+        self.token_balances.to_vec()
+    }
+
+    /**
+     * NEP141 backing the receipt tokens of account_id, returned on withdraw
+     */
+    pub fn get_token_contributions(&self, account_id: AccountId) -> Vec<TokenContribution> {
+        self.contributions
+            .get(&account_id)
+            .map_or(vec![], |contribution| contribution.tokens)
+    }
+
+    pub fn get_proposal(&self) -> Option<Proposal> {
+        self.proposal.clone()
+    }
+
+    /**
+     * Vote of account_id, on the open proposal or an older one
+     */
+    pub fn get_vote(&self, account_id: AccountId) -> Option<Vote> {
+        self.votes.get(&account_id)
+    }
+
+    pub fn get_income(&self) -> Income {
+        self.income.clone()
+    }
+
+    /**
+     * Income earned by account_id and not claimed yet, in nep_141
+     */
+    pub fn get_claimable_income(&self, account_id: AccountId) -> U128 {
+        U128(self.get_income_account(&account_id).unclaimed)
+    }
+
+    pub fn get_buyback(&self) -> Option<Buyback> {
+        self.buyback.clone()
+    }
+
+    pub fn get_settlement(&self) -> Option<Settlement> {
+        self.settlement.clone()
+    }
+
+    pub fn get_snapshot(&self, snapshot_id: u64) -> Option<Snapshot> {
+        snapshot_id
+            .checked_sub(1)
+            .and_then(|index| self.snapshots.get(index))
+    }
+
+    /**
+     * Receipt balance of account_id when snapshot_id was taken
+     */
+    pub fn balance_of_at(&self, account_id: AccountId, snapshot_id: u64) -> U128 {
+        let checkpoints = self.get_balance_checkpoints(&account_id);
+        let current = self.ft.accounts.get(&account_id).unwrap_or(0);
+
+        U128(self.checkpoint_at(&checkpoints, snapshot_id, current))
+    }
+
+    /**
+     * Receipt supply when snapshot_id was taken
+     */
+    pub fn total_supply_at(&self, snapshot_id: u64) -> U128 {
+        U128(self.checkpoint_at(&self.supply_checkpoints, snapshot_id, self.ft.total_supply))
+    }
+
+    /**
+     * Reconciles the receipt supply with the token contributions backing it,
+     * and lists what the escrow owes and should hold in each NEP141
+     * balanced is set when every receipt token is backed
+     */
+    pub fn get_accounting_report(&self) -> AccountingReport {
+        let backed_supply: Balance = self
+            .contributions
+            .values()
+            .map(|contribution| {
+                contribution
+                    .tokens
+                    .iter()
+                    .map(|token| token.receipts)
+                    .sum::<Balance>()
+            })
+            .sum();
+
+        let mut token_ids: Vec<AccountId> = self.token_balances.keys().collect();
+        let nep_141 = self.get_metadata().nep_141;
+
+        if !token_ids.contains(&nep_141) {
+            token_ids.insert(0, nep_141);
+        }
+
+        AccountingReport {
+            total_supply: U128(self.ft.total_supply),
+            backed_supply: U128(backed_supply),
+            total_funds: U128(self.get_total_funds()),
+            fees_amount: U128(self.fees.amount),
+            fee_mint_policy: self.fees.mint_policy,
+            tokens: token_ids
+                .iter()
+                .map(|token_id| self.get_token_accounting(token_id))
+                .collect(),
+            balanced: self.ft.total_supply == backed_supply,
+        }
+    }
+
+    pub fn get_pause(&self) -> Pause {
+        self.pause.clone()
+    }
+
+    pub fn get_guardian_account_id(&self) -> AccountId {
+        self.get_metadata()
+            .guardian_account_id
+            .unwrap_or(self.get_metadata().maintainer_account_id)
+    }
+
+    pub fn get_pending_guardian_account_id(&self) -> Option<AccountId> {
+        self.pending_guardian_account_id.clone()
+    }
+
+    pub fn get_referral_stats(&self, account_id: AccountId) -> Option<ReferralStats> {
+        self.referrals.get(&account_id)
+    }
+
     pub fn get_metadata(&self) -> Metadata {
         self.metadata.clone()
     }